@@ -0,0 +1,1389 @@
+use rusoto_core::{HttpClient, Region};
+use rusoto_credential::ProfileProvider;
+use rusoto_s3::S3Client;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tempfile::{Builder, TempDir};
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+use super::compress;
+use super::config::{self, RemoteConfig};
+use super::errors::*;
+use super::packs;
+use super::storage::{self, Storage};
+use super::{
+    compression, decode_metadata, delete_head, download_bundle, git, gpg, heads_object_format,
+    list_remote_refs, local_recipients, object_format, open_url, push_to_s3, recipients,
+    recipients_metadata, s3_client, s3_region, Encoding, GitRef, PushOptions, RemoteRef,
+    RemoteRefs, Settings,
+};
+
+const USAGE: &str = "usage: git-remote-s3 admin <command> <remote> [<args>]
+
+commands:
+    init <remote> [--recipients=<emails>] [--force] [--packs] [--snapshots]
+                                write the config shared by every clone of the
+                                remote: the gpg recipients to encrypt for
+                                (comma separated, defaults to this clone's),
+                                with --packs store history shared between refs
+                                once (empty remotes only), and with --snapshots
+                                upload the latest commit of each push on its
+                                own too, for shallow clones
+    ls <remote>                 list every head of every ref
+    migrate <remote> [--yes]    move the bundles to the latest storage layout,
+                                resuming an interrupted migration
+    heads <remote> <ref>        show who pushed each head of a ref
+    export <remote> <dir>       fetch every head, including old ones as
+                                <ref>__<sha>, into a new bare repository
+    get <remote> <ref> <file>   download and decrypt the bundle of a head
+    import <dir> <remote>       push every ref of a repository (as written by
+                                export) to an empty remote
+    mirror <src> <dst> [--recipients=<emails>] [--src-endpoint=<url>]
+           [--src-region=<region>] [--src-profile=<name>] [--dst-...]
+                                copy every head missing from <dst>, optionally
+                                re-encrypting for other recipients. Each side
+                                can use its own endpoint, region and aws
+                                credentials profile
+    prune <remote> [--force --older-than=<age>] [--yes]
+                                delete old heads that are merged into the latest
+                                head, and with --force unmerged ones older than
+                                <age> (e.g. 30d, 12h)
+    gc <remote> [--grace=<age>] repack the history of every head into one pack
+                                (remotes using packs), deleting packs that have
+                                been unused for <age> (default 1h)
+    recipients <remote>         show which gpg keys can decrypt each head,
+                                without decrypting it
+    rekey <remote> [--yes]      re-encrypt every bundle for the remote's current
+                                gpg recipients
+    verify <remote> [--sample=<n>]
+                                check that every object (or a random sample of
+                                n) is a bundle that decrypts, is valid and
+                                matches its key
+
+<remote> is either a url (s3://bucket/prefix or file:///dir) or the name of a
+git remote.
+<ref> is a ref or branch name; old heads can be named as <ref>__<sha>.";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = Args::parse(args);
+    match args
+        .positional
+        .iter()
+        .map(String::as_str)
+        .collect_vec()
+        .as_slice()
+    {
+        ["init", remote] => {
+            args.allow(&["recipients", "force", "packs", "snapshots"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_init(&*s3, &settings, &args)
+        }
+        ["ls", remote] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_ls(&*s3, &settings)
+        }
+        ["heads", remote, name] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_heads(&*s3, &settings, name)
+        }
+        ["export", remote, dir] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_export(&*s3, &settings, Path::new(dir))
+        }
+        ["get", remote, name, file] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_get(&*s3, &settings, name, Path::new(file))
+        }
+        ["import", dir, remote] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_import(&*s3, &settings, Path::new(dir))
+        }
+        ["migrate", remote] => {
+            args.allow(&["yes"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_migrate(&*s3, &settings, &args)
+        }
+        ["mirror", src, dst] => {
+            args.allow(&[
+                "recipients",
+                "src-endpoint",
+                "src-region",
+                "src-profile",
+                "dst-endpoint",
+                "dst-region",
+                "dst-profile",
+            ])?;
+            cmd_mirror(src, dst, &args)
+        }
+        ["prune", remote] => {
+            args.allow(&["force", "older-than", "yes"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_prune(&*s3, &settings, &args)
+        }
+        ["recipients", remote] => {
+            args.allow(&[])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_recipients(&*s3, &settings)
+        }
+        ["rekey", remote] => {
+            args.allow(&["yes"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_rekey(&*s3, &settings, &args)
+        }
+        ["gc", remote] => {
+            args.allow(&["grace"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_gc(&*s3, &settings, &args)
+        }
+        ["verify", remote] => {
+            args.allow(&["sample"])?;
+            let (s3, settings) = open(remote, s3_client())?;
+            cmd_verify(&*s3, &settings, &args)
+        }
+        _ => bail!("{}", USAGE),
+    }
+}
+
+// Flags that take a value, which can be given as `--flag value` or `--flag=value`
+const VALUE_FLAGS: &[&str] = &[
+    "grace",
+    "older-than",
+    "recipients",
+    "sample",
+    "src-endpoint",
+    "src-region",
+    "src-profile",
+    "dst-endpoint",
+    "dst-region",
+    "dst-profile",
+];
+
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Args {
+        let mut positional = vec![];
+        let mut flags = HashMap::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg.to_owned());
+                continue;
+            }
+            let (name, value) = match arg.find('=') {
+                Some(idx) => (&arg[2..idx], Some(arg[(idx + 1)..].to_string())),
+                None => (&arg[2..], None),
+            };
+            let value = match value {
+                Some(value) => value,
+                None if VALUE_FLAGS.contains(&name) => iter.next().cloned().unwrap_or_default(),
+                None => String::new(),
+            };
+            flags.insert(name.to_string(), value);
+        }
+        Args { positional, flags }
+    }
+
+    fn allow(&self, allowed: &[&str]) -> Result<()> {
+        match self.flags.keys().find(|f| !allowed.contains(&f.as_str())) {
+            Some(flag) => bail!("unknown option --{}\n\n{}", flag, USAGE),
+            None => Ok(()),
+        }
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
+    }
+}
+
+fn warn(message: &str) {
+    eprintln!("warning: {}", message);
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush().chain_err(|| "write error")?;
+    let mut input = String::new();
+    io::stdin()
+        .read_line(&mut input)
+        .chain_err(|| "read error")?;
+    Ok(input.trim().eq_ignore_ascii_case("y") || input.trim().eq_ignore_ascii_case("yes"))
+}
+
+// Ages are given as a number and a unit, e.g. 30d
+fn parse_age(age: &str) -> Result<Duration> {
+    let invalid = || format!("invalid age {:?}, expected e.g. 30d, 12h or 90m", age);
+    if age.is_empty() {
+        bail!(invalid());
+    }
+    let (n, unit) = age.split_at(age.len() - 1);
+    let n: i64 = n.parse().chain_err(invalid)?;
+    match unit {
+        "w" => Ok(Duration::weeks(n)),
+        "d" => Ok(Duration::days(n)),
+        "h" => Ok(Duration::hours(n)),
+        "m" => Ok(Duration::minutes(n)),
+        "s" => Ok(Duration::seconds(n)),
+        _ => bail!(invalid()),
+    }
+}
+
+// Heads need to be downloaded to compare them, which is done in a scratch
+// repository (used by every git command on this thread while it's kept) so
+// the current one is left untouched. It uses the hash algorithm of the heads,
+// if known.
+struct Scratch {
+    dir: TempDir,
+    _git_dir: git::UseDir,
+}
+
+impl Scratch {
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+fn scratch_repo(object_format: Option<&str>) -> Result<Scratch> {
+    let dir = Builder::new()
+        .prefix("s3_admin")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    git::init_bare(dir.path(), object_format)?;
+    let git_dir = git::use_dir(dir.path());
+    Ok(Scratch {
+        dir,
+        _git_dir: git_dir,
+    })
+}
+
+fn unbundle_head(s3: &dyn Storage, settings: &Settings, r: &RemoteRef) -> Result<()> {
+    if settings.layout() == config::LAYOUT_PACKS {
+        return packs::fetch(s3, settings, &r.reference);
+    }
+    let tmp_dir = Builder::new()
+        .prefix("s3_admin")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    let bundle_file = tmp_dir.path().join("bundle");
+    download_bundle(s3, &r.object, &bundle_file)?;
+    git::bundle_unbundle(&bundle_file, &r.reference.name)
+}
+
+// Resolve a url or remote name to the same settings git would run the helper
+// with. The client is used if the remote is on s3.
+fn open(remote: &str, client: S3Client) -> Result<(Box<dyn Storage>, Settings)> {
+    let (alias, url) = if remote.contains("://") {
+        (String::new(), remote.to_string())
+    } else {
+        let url = git::config(&format!("remote.{}.url", remote))
+            .chain_err(|| format!("no url configured for remote {}", remote))?;
+        // Directory remotes are configured as s3::file:///dir, to reach this helper
+        let url = url.strip_prefix("s3::").unwrap_or(&url).to_string();
+        (remote.to_string(), url)
+    };
+    let (s3, root) = open_url(&url, client)?;
+    let settings = Settings {
+        remote_alias: alias,
+        config: config::load(&*s3, &root)?,
+        root,
+        warn: Some(warn),
+        warned_ignored: Once::new(),
+    };
+    Ok((s3, settings))
+}
+
+// Accept short branch names, as git does
+fn full_ref_name(name: &str) -> String {
+    if name.starts_with("refs/") {
+        name.to_string()
+    } else {
+        format!("refs/heads/{}", name)
+    }
+}
+
+// Find a head by the name `list` advertises it as: the ref name for the latest
+// head, or `<ref>__<short_sha>` for the others
+fn find_head<'a>(refs: &'a HashMap<String, RemoteRefs>, name: &str) -> Result<&'a RemoteRef> {
+    let name = full_ref_name(name);
+    if let Some(remote_refs) = refs.get(&name) {
+        return Ok(remote_refs.latest_ref());
+    }
+    refs.values()
+        .flat_map(|rs| rs.latest_first.iter())
+        .find(|r| r.reference.stale_name() == name)
+        .chain_err(|| format!("no heads found for {}", name))
+}
+
+fn cmd_ls(s3: &dyn Storage, settings: &Settings) -> Result<()> {
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    for (_name, remote_refs) in all_remote_refs.iter().sorted_by_key(|(name, _)| *name) {
+        for (i, r) in remote_refs.latest_first.iter().enumerate() {
+            let name = if i == 0 {
+                r.reference.name.to_owned()
+            } else {
+                r.reference.stale_name()
+            };
+            println!("{}\t{}\t{}\t{}", r.reference.sha, name, r.size, r.updated);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_heads(s3: &dyn Storage, settings: &Settings, name: &str) -> Result<()> {
+    let name = full_ref_name(name);
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    let remote_refs = all_remote_refs
+        .get(&name)
+        .chain_err(|| format!("no heads found for {}", name))?;
+
+    for (i, r) in remote_refs.latest_first.iter().enumerate() {
+        let head = s3.head(&r.object)?;
+        let state = if i == 0 { "latest" } else { "stale" };
+        println!("{} {} ({})", r.reference.sha, r.reference.name, state);
+        println!("    last-modified: {}", r.updated);
+        for (key, value) in head.metadata.iter().sorted() {
+            println!("    {}: {}", key, decode_metadata(value));
+        }
+    }
+    Ok(())
+}
+
+fn cmd_get(s3: &dyn Storage, settings: &Settings, name: &str, file: &Path) -> Result<()> {
+    packs::unsupported(settings, "get")?;
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    let head = find_head(&all_remote_refs, name)?;
+    download_bundle(s3, &head.object, file)?;
+    eprintln!(
+        "wrote bundle for {} {} to {}",
+        head.reference.name,
+        head.reference.sha,
+        file.display()
+    );
+    Ok(())
+}
+
+fn cmd_prune(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    let force = args.flag("force");
+    // Unmerged heads are only deleted with --force, whatever their age
+    let older_than = match (args.value("older-than"), force) {
+        (Some(age), true) => Some(parse_age(age)?),
+        (None, true) => bail!("--force requires --older-than=<age>"),
+        (Some(_), false) => bail!("--older-than requires --force"),
+        (None, false) => None,
+    };
+
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    if !all_remote_refs.values().any(|rs| rs.latest_first.len() > 1) {
+        println!("no refs have old heads");
+        return Ok(());
+    }
+
+    // List again once every head is available, so the latest head is picked
+    // using the commit graph just as a client that has fetched them all would
+    let _scratch = scratch_repo(object_format(settings, &all_remote_refs).as_deref())?;
+    for remote_refs in all_remote_refs
+        .values()
+        .filter(|rs| rs.latest_first.len() > 1)
+    {
+        for r in remote_refs.latest_first.iter() {
+            unbundle_head(s3, settings, r)?;
+        }
+    }
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+
+    let now = Utc::now();
+    let mut to_delete = vec![];
+    for (name, remote_refs) in all_remote_refs
+        .iter()
+        .filter(|(_, rs)| rs.latest_first.len() > 1)
+        .sorted_by_key(|(name, _)| *name)
+    {
+        let latest = remote_refs.latest_ref();
+        println!("{}: latest head {}", name, latest.reference.sha);
+        for r in remote_refs.latest_first.iter().skip(1) {
+            let pushed = r.pushed_at.as_ref().unwrap_or(&r.updated);
+            let age = DateTime::parse_from_rfc3339(pushed)
+                .map(|t| now.signed_duration_since(t))
+                .chain_err(|| format!("invalid time {} for {}", pushed, r.object.key))?;
+            // A head that another head includes can go too, as nothing is lost
+            let mut merged_into = None;
+            for other in remote_refs.latest_first.iter() {
+                if other.reference.sha != r.reference.sha
+                    && git::is_ancestor(&other.reference.sha, &r.reference.sha)?
+                {
+                    merged_into = Some(other);
+                    break;
+                }
+            }
+            let action = if let Some(other) = merged_into {
+                to_delete.push(r);
+                format!("delete (merged into {})", other.reference.sha)
+            } else if older_than.is_some_and(|older_than| age > older_than) {
+                to_delete.push(r);
+                "delete (not merged, pushed before the cutoff)".to_string()
+            } else {
+                "keep (not merged)".to_string()
+            };
+            println!(
+                "    {} {} pushed {} days ago: {}",
+                r.reference.sha,
+                r.reference.stale_name(),
+                age.num_days(),
+                action
+            );
+        }
+    }
+
+    if to_delete.is_empty() {
+        println!("nothing to prune");
+        return Ok(());
+    }
+    if !args.flag("yes") && !confirm(&format!("delete {} heads?", to_delete.len()))? {
+        bail!("aborted");
+    }
+    for r in to_delete {
+        delete_head(s3, settings, r)?;
+        println!("deleted {}", r.object.key);
+    }
+    Ok(())
+}
+
+fn cmd_init(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    let url = s3.url(&settings.root);
+    if settings.config.is_some() && !args.flag("force") {
+        bail!(
+            "{} is already initialised, use --force to replace its config",
+            url
+        );
+    }
+    let recipients = match args.value("recipients") {
+        Some(list) => parse_recipients(list),
+        None => match local_recipients(settings) {
+            Some(recipients) => recipients,
+            None => vec![git::config("user.email")?],
+        },
+    };
+    if recipients.is_empty() {
+        bail!("no recipients given");
+    }
+
+    // Existing bundles stay where they are until the remote is migrated, and
+    // only an empty remote can switch to packs
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    let empty = all_remote_refs.is_empty();
+    let layout = match &settings.config {
+        _ if args.flag("packs") && (empty || settings.layout() == config::LAYOUT_PACKS) => {
+            config::LAYOUT_PACKS
+        }
+        _ if args.flag("packs") => bail!("{} has refs, only an empty remote can use packs", url),
+        Some(config) => config.layout,
+        None if empty => config::LAYOUT_DEFAULT,
+        None => config::LAYOUT_REF_BUNDLES,
+    };
+
+    // That of the heads already pushed, otherwise of this repository
+    let object_format = match heads_object_format(&all_remote_refs) {
+        Some(format) => format.to_string(),
+        None => git::object_format().unwrap_or_else(|_| config::OBJECT_FORMAT_SHA1.to_string()),
+    };
+
+    let mut config = RemoteConfig::new(recipients.to_owned(), layout);
+    config.snapshots = args.flag("snapshots");
+    config.object_format = object_format;
+    if args.flag("force") {
+        config::save(s3, &settings.root, &config)?;
+    } else if !config::create(s3, &settings.root, &config)? {
+        bail!("{} was initialised by someone else meanwhile", url);
+    }
+    println!(
+        "initialised {} (layout {}, {}) for {}",
+        url,
+        layout,
+        config.object_format,
+        recipients.join(" ")
+    );
+    if settings.config.is_some() {
+        println!("existing bundles keep their encryption, run `rekey` to re-encrypt them");
+    }
+    if layout < config::LAYOUT_DEFAULT {
+        println!("run `migrate` to move the bundles to the latest layout");
+    }
+    Ok(())
+}
+
+fn parse_recipients(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_ascii_whitespace())
+        .filter(|r| !r.is_empty())
+        .map(|r| r.to_string())
+        .collect_vec()
+}
+
+// git commands are run with GIT_DIR set, which needs to be absolute
+fn absolute(dir: &Path) -> Result<PathBuf> {
+    Ok(env::current_dir()
+        .chain_err(|| "could not get pwd")?
+        .join(dir))
+}
+
+fn cmd_export(s3: &dyn Storage, settings: &Settings, dir: &Path) -> Result<()> {
+    let dir = absolute(dir)?;
+    if dir.exists() && dir.read_dir().map_or(true, |mut d| d.next().is_some()) {
+        bail!("{} already exists and is not empty", dir.display());
+    }
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+
+    git::init_bare(&dir, object_format(settings, &all_remote_refs).as_deref())?;
+    let _git_dir = git::use_dir(&dir);
+    for r in all_remote_refs
+        .values()
+        .flat_map(|rs| rs.latest_first.iter())
+    {
+        unbundle_head(s3, settings, r)?;
+    }
+    // List again now that the latest heads can be picked using the commit graph
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    let mut exported = 0;
+    for (_name, remote_refs) in all_remote_refs.iter().sorted_by_key(|(name, _)| *name) {
+        for (i, r) in remote_refs.latest_first.iter().enumerate() {
+            let name = if i == 0 {
+                r.reference.name.to_owned()
+            } else {
+                r.reference.stale_name()
+            };
+            git::update_ref(&name, &r.reference.sha)?;
+            println!("{} {}", r.reference.sha, name);
+            exported += 1;
+        }
+    }
+    println!("exported {} heads to {}", exported, dir.display());
+    Ok(())
+}
+
+// Refs are pushed with a single list of the remote beforehand, rather than the
+// checks of a normal push. Old heads named <ref>__<sha> by export are pushed
+// as heads of <ref>, before its latest head.
+fn cmd_import(s3: &dyn Storage, settings: &Settings, dir: &Path) -> Result<()> {
+    let url = s3.url(&settings.root);
+    if !list_remote_refs(s3, settings)?.is_empty() {
+        bail!("{} already has refs, import needs an empty remote", url);
+    }
+    let recipients = recipients(settings)?;
+
+    let dir = absolute(dir)?;
+    let _git_dir = git::use_dir(&dir);
+    let local_format = git::object_format()?;
+    if let Some(format) = settings.config.as_ref().map(|c| &c.object_format) {
+        if *format != local_format {
+            bail!(
+                "{} holds {} repositories, and {} is {}",
+                url,
+                format,
+                dir.display(),
+                local_format
+            );
+        }
+    }
+    let refs = git::for_each_ref()?
+        .into_iter()
+        .filter(|(_, name)| !name.ends_with("/HEAD"))
+        .map(|(sha, name)| {
+            let stale = stale_ref_name(&name, &sha);
+            (stale.is_none(), stale.unwrap_or(&name).to_string(), sha)
+        })
+        .sorted()
+        .collect_vec();
+
+    // Heads are given their ref name in a scratch repository sharing the
+    // objects of the imported one, as a bundle names the refs it contains
+    let scratch = scratch_repo(Some(&local_format))?;
+    let alternates = scratch
+        .path()
+        .join("objects")
+        .join("info")
+        .join("alternates");
+    fs::write(&alternates, format!("{}\n", dir.join("objects").display()))
+        .chain_err(|| "couldn't write alternates")?;
+    // Stale heads go first, so that each ref's latest head is pushed last
+    let (latest, stale): (Vec<_>, Vec<_>) = refs
+        .iter()
+        .map(|(latest, name, sha)| {
+            let r = GitRef {
+                name: name.to_owned(),
+                sha: sha.to_owned(),
+            };
+            (*latest, r)
+        })
+        .partition(|(latest, _)| *latest);
+    for heads in [stale, latest].iter() {
+        in_parallel(heads, |(_, r)| {
+            git::update_ref(&r.name, &r.sha)?;
+            push_to_s3(s3, settings, r, None, &recipients, &PushOptions::default())?;
+            println!("{} {}", r.sha, r.name);
+            Ok(())
+        })?;
+    }
+    println!("imported {} heads to {}", refs.len(), url);
+    Ok(())
+}
+
+// How many heads are bundled, encrypted and uploaded at once
+const UPLOAD_JOBS: usize = 4;
+
+// Run f for every item, a few at a time, in the repository git commands use
+// on this thread. After an error, items that haven't started are skipped.
+fn in_parallel<T: Sync>(items: &[T], f: impl Fn(&T) -> Result<()> + Sync) -> Result<()> {
+    let queue = Mutex::new(items.iter());
+    let git_dir = git::current_dir();
+    thread::scope(|scope| {
+        let workers = (0..UPLOAD_JOBS)
+            .map(|_| {
+                scope.spawn(|| {
+                    let _git_dir = git_dir.as_deref().map(git::use_dir);
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let item = match next {
+                            Some(item) => item,
+                            None => return Ok(()),
+                        };
+                        if let Err(e) = f(item) {
+                            queue.lock().unwrap().by_ref().for_each(drop);
+                            return Err(e);
+                        }
+                    }
+                })
+            })
+            .collect_vec();
+        // Any not joined here are joined by the scope
+        workers.into_iter().try_for_each(|w| w.join().unwrap())
+    })
+}
+
+// The ref an old head exported as <ref>__<sha> belongs to
+fn stale_ref_name<'a>(name: &'a str, sha: &str) -> Option<&'a str> {
+    let (name, short_sha) = name.rsplit_once("__")?;
+    if name.is_empty() || short_sha.len() != 7 || !sha.starts_with(short_sha) {
+        return None;
+    }
+    Some(name)
+}
+
+// Records the packs no head uses any more, and since when, on the remote
+const GC_JOURNAL_NAME: &str = ".git-remote-s3-gc.json";
+// Longer than any fetch or push, which may still use packs they listed
+// before gc replaced them
+const GC_GRACE: &str = "1h";
+
+#[derive(Default, Serialize, Deserialize)]
+struct GcJournal {
+    // Pack ids, and when gc first found them unused
+    unused: BTreeMap<String, String>,
+}
+
+// Heads are switched to the new pack before anything is deleted, and the
+// packs they used are only deleted once they have been unused for the grace
+// period (by this or a later gc), so fetches and pushes that read the old
+// manifests can finish
+fn cmd_gc(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    if settings.layout() != config::LAYOUT_PACKS {
+        bail!(
+            "gc only applies to remotes using packs (layout {}), see `init --packs`",
+            config::LAYOUT_PACKS
+        );
+    }
+    let grace = parse_age(args.value("grace").unwrap_or(GC_GRACE))?;
+    let journal_key = storage::Key {
+        bucket: settings.root.bucket.to_owned(),
+        key: format!("{}/{}", settings.root.key, GC_JOURNAL_NAME),
+    };
+    let mut journal: GcJournal = match s3.get_bytes(&journal_key)? {
+        Some(data) => serde_json::from_slice(&data).chain_err(|| "invalid gc journal")?,
+        None => GcJournal::default(),
+    };
+
+    let used = packs::referenced(s3, settings)?;
+    if used.len() > 1 {
+        let recipients = recipients(settings)?;
+        let encoding = Encoding {
+            recipients: &recipients,
+            compression: compression(settings)?,
+            storage_class: None,
+        };
+        let all_remote_refs = list_remote_refs(s3, settings)?;
+        let heads = all_remote_refs
+            .values()
+            .flat_map(|rs| rs.latest_first.iter())
+            .collect_vec();
+        let _scratch = scratch_repo(object_format(settings, &all_remote_refs).as_deref())?;
+        let pack = packs::repack(s3, settings, &heads, &encoding)?;
+        println!(
+            "repacked {} packs of {} heads into {}",
+            used.len(),
+            heads.len(),
+            pack.id
+        );
+    } else {
+        println!("nothing to repack");
+    }
+
+    // Listed again, as a push may have started using a pack meanwhile
+    let used = packs::referenced(s3, settings)?;
+    let now = Utc::now();
+    let stamp = now.to_rfc3339_opts(SecondsFormat::Millis, true);
+    journal.unused.retain(|id, _| !used.contains(id));
+    for pack in packs::list_packs(s3, settings)? {
+        if !used.contains(&pack.id) {
+            journal.unused.entry(pack.id).or_insert(stamp.to_owned());
+        }
+    }
+    let mut deleted = 0;
+    for (id, since) in journal.unused.clone() {
+        let since = DateTime::parse_from_rfc3339(&since)
+            .chain_err(|| format!("invalid time {} for pack {}", since, id))?;
+        if now.signed_duration_since(since) >= grace {
+            packs::delete(s3, settings, &id)?;
+            journal.unused.remove(&id);
+            deleted += 1;
+        }
+    }
+
+    if journal.unused.is_empty() {
+        s3.del(&journal_key)?;
+    } else {
+        let data = serde_json::to_vec_pretty(&journal).chain_err(|| "serialize failed")?;
+        s3.put_bytes(data, &journal_key, &HashMap::new(), None)?;
+    }
+    println!("deleted {} unused packs", deleted);
+    if !journal.unused.is_empty() {
+        println!(
+            "{} packs will be deleted by gc once unused for {}",
+            journal.unused.len(),
+            args.value("grace").unwrap_or(GC_GRACE)
+        );
+    }
+    Ok(())
+}
+
+// Records a migration in progress on the remote, so it can be resumed
+const MIGRATE_JOURNAL_NAME: &str = ".git-remote-s3-migrate.json";
+
+#[derive(Serialize, Deserialize)]
+struct MigrateJournal {
+    from: u32,
+    to: u32,
+    // Keys of the bundles in the old layout that have been copied
+    copied: Vec<String>,
+    // Once the config points at the new layout, the old bundles can go
+    switched: bool,
+}
+
+// Bundles are copied to the new layout before the config is switched over and
+// only deleted after, so the remote stays usable throughout
+fn cmd_migrate(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    let url = s3.url(&settings.root);
+    let journal_key = storage::Key {
+        bucket: settings.root.bucket.to_owned(),
+        key: format!("{}/{}", settings.root.key, MIGRATE_JOURNAL_NAME),
+    };
+    let mut journal = match s3.get_bytes(&journal_key)? {
+        Some(data) => {
+            let journal: MigrateJournal =
+                serde_json::from_slice(&data).chain_err(|| "invalid migration journal")?;
+            println!(
+                "resuming migration from layout {} to {}",
+                journal.from, journal.to
+            );
+            journal
+        }
+        None => {
+            let from = settings.layout();
+            if from >= config::LAYOUT_DEFAULT {
+                println!("already using the latest layout ({})", from);
+                return Ok(());
+            }
+            let prompt = format!("migrate {} from layout {} to {}?", url, from, from + 1);
+            if !args.flag("yes") && !confirm(&prompt)? {
+                bail!("aborted");
+            }
+            MigrateJournal {
+                from,
+                to: from + 1,
+                copied: vec![],
+                switched: false,
+            }
+        }
+    };
+    let config = match &settings.config {
+        Some(config) => config.clone(),
+        // Remotes without a config hold repositories of their heads' hash algorithm
+        None => {
+            let mut config = RemoteConfig::new(vec![], journal.from);
+            if let Some(format) = heads_object_format(&list_remote_refs(s3, settings)?) {
+                config.object_format = format.to_string();
+            }
+            config
+        }
+    };
+    let old = with_layout(settings, &config, journal.from);
+    let new = with_layout(settings, &config, journal.to);
+
+    if !journal.switched {
+        copy_to_layout(s3, &old, &new, &journal_key, &mut journal)?;
+        config::save(s3, &settings.root, new.config.as_ref().unwrap())?;
+        journal.switched = true;
+        save_journal(s3, &journal_key, &journal)?;
+        println!("switched to layout {}", journal.to);
+    }
+    // Clients that read the config before the switch may have pushed since,
+    // or may push while the old heads are deleted
+    loop {
+        let heads = copy_to_layout(s3, &old, &new, &journal_key, &mut journal)?;
+        if heads.is_empty() {
+            break;
+        }
+        for r in heads {
+            s3.del(&r.object)?;
+            println!("deleted {}", r.object.key);
+        }
+    }
+    s3.del(&journal_key)?;
+    println!("migrated {} to layout {}", url, journal.to);
+    Ok(())
+}
+
+fn with_layout(settings: &Settings, config: &RemoteConfig, layout: u32) -> Settings {
+    let mut config = config.clone();
+    config.layout = layout;
+    Settings {
+        remote_alias: settings.remote_alias.to_owned(),
+        root: settings.root.clone(),
+        config: Some(config),
+        warn: settings.warn,
+        warned_ignored: Once::new(),
+    }
+}
+
+// Copy every head not yet copied, returning all the heads in the old layout
+fn copy_to_layout(
+    s3: &dyn Storage,
+    old: &Settings,
+    new: &Settings,
+    journal_key: &storage::Key,
+    journal: &mut MigrateJournal,
+) -> Result<Vec<RemoteRef>> {
+    let heads = list_remote_refs(s3, old)?
+        .into_values()
+        .flat_map(|rs| rs.latest_first)
+        .sorted_by(|a, b| a.object.key.cmp(&b.object.key))
+        .collect_vec();
+    for r in heads.iter() {
+        if journal.copied.contains(&r.object.key) {
+            continue;
+        }
+        let head = s3.head(&r.object)?;
+        s3.copy(
+            &r.object,
+            &new.bundle_key(&r.reference),
+            &keep_push_time(head.metadata, r),
+            head.storage_class.as_deref(),
+        )?;
+        journal.copied.push(r.object.key.to_owned());
+        save_journal(s3, journal_key, journal)?;
+        println!("{}: copied", r.object.key);
+    }
+    Ok(heads)
+}
+
+// A copy is modified when it's made, so heads that don't record when they were
+// pushed keep their order by recording when the original was
+fn keep_push_time(mut metadata: HashMap<String, String>, r: &RemoteRef) -> HashMap<String, String> {
+    if !r.updated.is_empty() {
+        metadata
+            .entry("pushed-at".to_string())
+            .or_insert_with(|| r.updated.to_owned());
+    }
+    metadata
+}
+
+fn save_journal(s3: &dyn Storage, key: &storage::Key, journal: &MigrateJournal) -> Result<()> {
+    let data = serde_json::to_vec(journal).chain_err(|| "serialize journal failed")?;
+    s3.put_bytes(data, key, &HashMap::new(), None)?;
+    Ok(())
+}
+
+// Heads are copied within s3 when both remotes are on the same service, and
+// otherwise downloaded and uploaded again (as is, unless re-encrypting). Heads
+// already on <dst> are skipped, so mirroring again only copies new pushes.
+fn cmd_mirror(src: &str, dst: &str, args: &Args) -> Result<()> {
+    let (src_client, src_service) = side_client(args, "src")?;
+    let (dst_client, dst_service) = side_client(args, "dst")?;
+    let (src_s3, src) = open(src, src_client)?;
+    let (dst_s3, mut dst) = open(dst, dst_client)?;
+    packs::unsupported(&src, "mirror")?;
+    packs::unsupported(&dst, "mirror")?;
+    let (src_s3, dst_s3) = (&*src_s3, &*dst_s3);
+    let same_service = src_s3.scheme() == dst_s3.scheme() && src_service == dst_service;
+    let recipients = args.value("recipients").map(parse_recipients);
+    if recipients.as_ref().is_some_and(|r| r.is_empty()) {
+        bail!("no recipients given");
+    }
+
+    // An uninitialised destination gets the source's config, so the same layout
+    match (&dst.config, &recipients) {
+        (None, _) => {
+            if let Some(mut config) = src.config.clone() {
+                if let Some(recipients) = &recipients {
+                    config.recipients = recipients.to_owned();
+                }
+                config::save(dst_s3, &dst.root, &config)?;
+                dst.config = Some(config);
+            }
+        }
+        (Some(config), Some(recipients))
+            if !config.recipients.is_empty()
+                && recipients_metadata(recipients) != recipients_metadata(&config.recipients) =>
+        {
+            bail!(
+                "--recipients conflicts with the recipients in the config of {} ({})",
+                dst_s3.url(&dst.root),
+                config.recipients.join(" ")
+            );
+        }
+        _ => (),
+    }
+
+    let src_refs = list_remote_refs(src_s3, &src)?;
+    let dst_refs = list_remote_refs(dst_s3, &dst)?;
+    if let (Some(src_format), Some(dst_format)) = (
+        object_format(&src, &src_refs),
+        object_format(&dst, &dst_refs),
+    ) {
+        if src_format != dst_format {
+            bail!(
+                "{} holds {} repositories, and {} holds {}",
+                src_s3.url(&src.root),
+                src_format,
+                dst_s3.url(&dst.root),
+                dst_format
+            );
+        }
+    }
+    let existing: HashSet<String> = dst_refs
+        .values()
+        .flat_map(|rs| rs.latest_first.iter())
+        .map(|r| r.object.key.to_owned())
+        .collect();
+    let heads = src_refs
+        .into_values()
+        .flat_map(|rs| rs.latest_first)
+        .sorted_by(|a, b| a.object.key.cmp(&b.object.key))
+        .collect_vec();
+
+    let mut mirrored = 0;
+    for r in heads {
+        let to = dst.bundle_key(&r.reference);
+        if existing.contains(&to.key) {
+            println!("{}: already mirrored", to.key);
+            continue;
+        }
+        // Before the head, as a push uploads it, so every head has its snapshot
+        if src.snapshots() && dst.snapshots() {
+            let from = src.snapshot_key(&r.reference);
+            let to = dst.snapshot_key(&r.reference);
+            match mirror_object(src_s3, dst_s3, &r, &from, &to, same_service, &recipients) {
+                Ok(how) => println!("{}: {}", to.key, how),
+                // Pushed by a client that doesn't upload snapshots
+                Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let how = mirror_object(
+            src_s3,
+            dst_s3,
+            &r,
+            &r.object,
+            &to,
+            same_service,
+            &recipients,
+        )?;
+        println!("{}: {}", to.key, how);
+        mirrored += 1;
+    }
+    println!("mirrored {} heads to {}", mirrored, dst_s3.url(&dst.root));
+    Ok(())
+}
+
+// Copy one object of a head, re-encrypting it for the recipients if given,
+// and say how
+fn mirror_object(
+    src_s3: &dyn Storage,
+    dst_s3: &dyn Storage,
+    r: &RemoteRef,
+    from: &storage::Key,
+    to: &storage::Key,
+    same_service: bool,
+    recipients: &Option<Vec<String>>,
+) -> Result<&'static str> {
+    let head = src_s3.head(from)?;
+    let mut metadata = keep_push_time(head.metadata, r);
+    let storage_class = head.storage_class.as_deref();
+
+    let tmp_dir = Builder::new()
+        .prefix("s3_mirror")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    let bundle_file = tmp_dir.path().join("bundle");
+    let enc_file = tmp_dir.path().join("bundle_enc");
+    match recipients {
+        None if same_service => {
+            dst_s3.copy(from, to, &metadata, storage_class)?;
+            Ok("copied")
+        }
+        None => {
+            src_s3.get(from, &enc_file)?;
+            dst_s3.put(&enc_file, to, &metadata, storage_class)?;
+            Ok("uploaded")
+        }
+        Some(recipients) => {
+            download_bundle(src_s3, from, &bundle_file)?;
+            gpg::encrypt(recipients, &bundle_file, &enc_file)?;
+            // gpg compresses the bundle it's given, which was decompressed
+            metadata.remove(compress::METADATA_KEY);
+            metadata.insert("recipients".to_string(), recipients_metadata(recipients));
+            dst_s3.put(&enc_file, to, &metadata, storage_class)?;
+            Ok("re-encrypted")
+        }
+    }
+}
+
+// The client for one side of a mirror, and what identifies its service and
+// credentials: objects can only be copied within s3 when both are the same
+fn side_client(args: &Args, side: &str) -> Result<(S3Client, Vec<Option<String>>)> {
+    let value = |name: &str| args.value(&format!("{}-{}", side, name));
+    let (endpoint, region, profile) = (value("endpoint"), value("region"), value("profile"));
+    let service = vec![endpoint, region, profile]
+        .into_iter()
+        .map(|v| v.map(|v| v.to_string()))
+        .collect_vec();
+    if service.iter().all(Option::is_none) {
+        return Ok((s3_client(), service));
+    }
+
+    let region = match (endpoint, region) {
+        (Some(endpoint), region) => Region::Custom {
+            name: region.unwrap_or("us-east-1").to_string(),
+            endpoint: endpoint.to_string(),
+        },
+        (None, Some(region)) => region
+            .parse()
+            .chain_err(|| format!("unknown region {}", region))?,
+        (None, None) => s3_region(),
+    };
+    let client = match profile {
+        Some(profile) => {
+            let mut provider =
+                ProfileProvider::new().chain_err(|| "couldn't load aws credentials profiles")?;
+            provider.set_profile(profile);
+            let dispatcher = HttpClient::new().chain_err(|| "couldn't create http client")?;
+            S3Client::new_with(dispatcher, provider, region)
+        }
+        None => S3Client::new(region),
+    };
+    Ok((client, service))
+}
+
+fn cmd_verify(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    packs::unsupported(settings, "verify")?;
+    let sample = match args.value("sample") {
+        Some(n) => Some(
+            n.parse::<usize>()
+                .chain_err(|| format!("invalid sample size {:?}", n))?,
+        ),
+        None => None,
+    };
+
+    let mut keys = s3
+        .list(&settings.dir_prefix(&settings.bundle_root()))?
+        .into_iter()
+        .map(|o| o.key)
+        .filter(|k| !settings.is_reserved(k))
+        .collect_vec();
+    if let Some(n) = sample {
+        let random = RandomState::new();
+        keys.sort_by_key(|k| random.hash_one(k));
+        keys.truncate(n);
+        keys.sort();
+    }
+
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    let _scratch = scratch_repo(object_format(settings, &all_remote_refs).as_deref())?;
+    let mut problems = 0;
+    for key in keys.iter() {
+        match verify_object(s3, settings, key)? {
+            Some(problem) => {
+                problems += 1;
+                println!("{}: {}", key, problem);
+            }
+            None => println!("{}: ok", key),
+        }
+    }
+    println!("verified {} objects, {} problems", keys.len(), problems);
+    if problems > 0 {
+        bail!("verify found {} problems", problems);
+    }
+    Ok(())
+}
+
+// Describe what is wrong with an object, if anything
+fn verify_object(s3: &dyn Storage, settings: &Settings, key: &str) -> Result<Option<String>> {
+    let r = match GitRef::from_bundle_path(&settings.bundle_root(), key, "bundle") {
+        Some(r) => r,
+        None => return Ok(Some("foreign (not a <ref>/<sha>.bundle key)".to_string())),
+    };
+
+    let tmp_dir = Builder::new()
+        .prefix("s3_verify")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    let bundle_file = tmp_dir.path().join("bundle");
+    let o = storage::Key {
+        bucket: settings.root.bucket.to_owned(),
+        key: key.to_string(),
+    };
+    match download_bundle(s3, &o, &bundle_file) {
+        Ok(()) => (),
+        Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::Crypto(_))) => {
+            return Ok(Some("undecryptable".to_string()));
+        }
+        Err(e) => return Err(e),
+    }
+    if !git::bundle_verify(&bundle_file)? {
+        return Ok(Some("corrupt (git bundle verify failed)".to_string()));
+    }
+    let heads = git::bundle_list_heads(&bundle_file)?;
+    Ok(match heads.iter().find(|(_, name)| *name == r.name) {
+        Some((sha, _)) if *sha == r.sha => None,
+        Some((sha, _)) => Some(format!("mis-named (bundle tip is {})", sha)),
+        None => Some(format!("mis-named (bundle does not contain {})", r.name)),
+    })
+}
+
+// Each bundle is replaced in place: s3 swaps in the new object in one go, so
+// readers see either the old or new encryption. Bundles record their
+// recipients, so running again after an interruption skips finished ones.
+fn cmd_rekey(s3: &dyn Storage, settings: &Settings, args: &Args) -> Result<()> {
+    let recipients = recipients(settings)?;
+    let marker = recipients_metadata(&recipients);
+    let mut recipient_keys = vec![];
+    for recipient in recipients.iter() {
+        let keys = gpg::encryption_keys(false, &[recipient.to_owned()])
+            .chain_err(|| format!("no public key found for recipient {}", recipient))?;
+        recipient_keys.push(keys);
+    }
+
+    let mut todo = vec![];
+    for (o, snapshot) in encrypted_objects(s3, settings)? {
+        let head = match s3.head(&o) {
+            Ok(head) => head,
+            // A snapshot that wasn't pushed, or a pack deleted by gc since listing
+            Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => continue,
+            Err(e) => return Err(e),
+        };
+        if encrypted_for(s3, &o, &recipient_keys)? {
+            println!("{}: already encrypted for {}", o.key, marker);
+        } else {
+            todo.push((o, snapshot, head.metadata, head.storage_class));
+        }
+    }
+
+    if todo.is_empty() {
+        println!("nothing to rekey");
+        return Ok(());
+    }
+    let prompt = format!("re-encrypt {} bundles for {}?", todo.len(), marker);
+    if !args.flag("yes") && !confirm(&prompt)? {
+        bail!("aborted");
+    }
+
+    for (o, snapshot, metadata, storage_class) in todo {
+        // The snapshot first, so that a failed rekey is retried for both
+        if let Some(snapshot) = snapshot {
+            match s3.head(&snapshot) {
+                Ok(head) => reencrypt(
+                    s3,
+                    &snapshot,
+                    &recipients,
+                    head.metadata,
+                    head.storage_class,
+                )?,
+                Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        reencrypt(s3, &o, &recipients, metadata, storage_class)?;
+        println!("{}: re-encrypted", o.key);
+    }
+    Ok(())
+}
+
+// Whether only the recipients can read an object, going by the keys it was
+// encrypted for rather than its `recipients` metadata, which a rekey that was
+// interrupted may have left on data that wasn't re-encrypted
+fn encrypted_for(
+    s3: &dyn Storage,
+    o: &storage::Key,
+    recipient_keys: &[HashMap<String, String>],
+) -> Result<bool> {
+    let data = s3.get_head_bytes(o, RECIPIENTS_READ_LEN)?;
+    let key_ids = match gpg::recipient_key_ids(&data) {
+        Ok(key_ids) => key_ids,
+        // Not encrypted, or not in a way that can be checked
+        Err(_) => return Ok(false),
+    };
+    let readable_by =
+        |keys: &HashMap<String, String>| key_ids.iter().any(|id| keys.contains_key(id));
+    let is_recipient = |id: &String| recipient_keys.iter().any(|keys| keys.contains_key(id));
+    Ok(recipient_keys.iter().all(readable_by) && key_ids.iter().all(is_recipient))
+}
+
+// Every head's bundle, along with its snapshot if the remote keeps them. The
+// heads of remotes using packs are unencrypted manifests, so there it's their
+// snapshots and then the packs.
+fn encrypted_objects(
+    s3: &dyn Storage,
+    settings: &Settings,
+) -> Result<Vec<(storage::Key, Option<storage::Key>)>> {
+    let heads = list_remote_refs(s3, settings)?
+        .into_values()
+        .flat_map(|rs| rs.latest_first)
+        .sorted_by(|a, b| a.object.key.cmp(&b.object.key))
+        .collect_vec();
+    let snapshot =
+        |r: &RemoteRef| Some(settings.snapshot_key(&r.reference)).filter(|_| settings.snapshots());
+    if settings.layout() != config::LAYOUT_PACKS {
+        return Ok(heads
+            .iter()
+            .map(|r| (r.object.to_owned(), snapshot(r)))
+            .collect());
+    }
+    let mut objects = heads
+        .iter()
+        .filter_map(snapshot)
+        .map(|o| (o, None))
+        .collect_vec();
+    for pack in packs::list_packs(s3, settings)? {
+        objects.push((packs::pack_key(settings, &pack.id, "bundle"), None));
+    }
+    Ok(objects)
+}
+
+fn reencrypt(
+    s3: &dyn Storage,
+    o: &storage::Key,
+    recipients: &[String],
+    mut metadata: HashMap<String, String>,
+    storage_class: Option<String>,
+) -> Result<()> {
+    let tmp_dir = Builder::new()
+        .prefix("s3_rekey")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    let bundle_file = tmp_dir.path().join("bundle");
+    let enc_file = tmp_dir.path().join("bundle_enc");
+
+    download_bundle(s3, o, &bundle_file)?;
+    gpg::encrypt(recipients, &bundle_file, &enc_file)?;
+    metadata.remove(compress::METADATA_KEY);
+    metadata.insert("recipients".to_string(), recipients_metadata(recipients));
+    s3.put(&enc_file, o, &metadata, storage_class.as_deref())
+}
+
+// Enough for the session key packets of dozens of recipients with large keys
+const RECIPIENTS_READ_LEN: usize = 64 * 1024;
+const HIDDEN_KEY_ID: &str = "0000000000000000";
+
+fn cmd_recipients(s3: &dyn Storage, settings: &Settings) -> Result<()> {
+    packs::unsupported(settings, "recipients")?;
+    let recipients = recipients(settings)?;
+    let mut recipient_keys = vec![];
+    for recipient in recipients.iter() {
+        let keys = gpg::encryption_keys(false, &[recipient.to_owned()]).unwrap_or_else(|_| {
+            eprintln!("warning: no public key found for recipient {}", recipient);
+            HashMap::new()
+        });
+        recipient_keys.push((recipient, keys));
+    }
+    let my_keys = gpg::encryption_keys(true, &[])?;
+    let known_keys = gpg::encryption_keys(false, &[])?;
+
+    let all_remote_refs = list_remote_refs(s3, settings)?;
+    for (_name, remote_refs) in all_remote_refs.iter().sorted_by_key(|(name, _)| *name) {
+        for (i, r) in remote_refs.latest_first.iter().enumerate() {
+            let name = if i == 0 {
+                r.reference.name.to_owned()
+            } else {
+                r.reference.stale_name()
+            };
+            println!("{} {}", r.reference.sha, name);
+
+            let data = s3.get_head_bytes(&r.object, RECIPIENTS_READ_LEN)?;
+            let key_ids = match gpg::recipient_key_ids(&data) {
+                Ok(key_ids) => key_ids,
+                Err(e) => {
+                    println!("    ! could not read recipients: {}", e);
+                    continue;
+                }
+            };
+            for key_id in key_ids.iter() {
+                let owner = match known_keys.get(key_id) {
+                    Some(user_id) => user_id.as_str(),
+                    None if key_id == HIDDEN_KEY_ID => "(hidden recipient)",
+                    None => "(unknown key)",
+                };
+                println!("    {} {}", key_id, owner);
+            }
+
+            let hidden = key_ids.iter().any(|id| id == HIDDEN_KEY_ID);
+            let can_read = |keys: &HashMap<String, String>| {
+                hidden || key_ids.iter().any(|id| keys.contains_key(id))
+            };
+            let missing = recipient_keys
+                .iter()
+                .filter(|(_, keys)| !can_read(keys))
+                .map(|(recipient, _)| recipient.as_str())
+                .collect_vec();
+            if !missing.is_empty() {
+                println!(
+                    "    ! not readable by current recipients: {}",
+                    missing.join(" ")
+                );
+            }
+            if !can_read(&my_keys) {
+                println!("    ! not readable by you");
+            }
+        }
+    }
+    Ok(())
+}
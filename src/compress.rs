@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::process::Command;
+
+use super::errors::*;
+use super::pipe::Stage;
+
+// The object metadata recording how a bundle was compressed
+pub const METADATA_KEY: &str = "compression";
+
+// How a bundle is compressed before it's encrypted, set with
+// `remote.<name>.compression`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    // Bundles hold packs whose objects are already deflated, so compressing
+    // them again mostly costs time
+    None,
+    Zstd,
+    // gpg's own compression, and what bundles pushed without the metadata use
+    GpgDefault,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Result<Compression> {
+        match name {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "gpg-default" => Ok(Compression::GpgDefault),
+            _ => bail!(
+                "unknown compression {}, expected none, zstd or gpg-default",
+                name
+            ),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::GpgDefault => "gpg-default",
+        }
+    }
+
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Result<Compression> {
+        match metadata.get(METADATA_KEY) {
+            Some(name) => Compression::parse(name),
+            None => Ok(Compression::GpgDefault),
+        }
+    }
+
+    // Whether gpg should compress what it encrypts
+    pub fn gpg_compresses(self) -> bool {
+        self == Compression::GpgDefault
+    }
+
+    // Compresses stdin to stdout, before it's encrypted
+    pub fn compress_stage(self) -> Option<Stage> {
+        match self {
+            Compression::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("-q").arg("-c");
+                Some(Stage::new(cmd, "zstd compress failed"))
+            }
+            _ => None,
+        }
+    }
+
+    // Decompresses stdin to stdout, after it's decrypted
+    pub fn decompress_stage(self) -> Option<Stage> {
+        match self {
+            Compression::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("-d").arg("-q").arg("-c");
+                Some(Stage::new(cmd, "zstd decompress failed"))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_without_metadata_use_gpg_compression() {
+        let mut metadata = HashMap::new();
+        assert_eq!(
+            Compression::from_metadata(&metadata).unwrap(),
+            Compression::GpgDefault
+        );
+        metadata.insert(METADATA_KEY.to_string(), "zstd".to_string());
+        assert_eq!(
+            Compression::from_metadata(&metadata).unwrap(),
+            Compression::Zstd
+        );
+        metadata.insert(METADATA_KEY.to_string(), "lz4".to_string());
+        assert!(Compression::from_metadata(&metadata).is_err());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for c in [
+            Compression::None,
+            Compression::Zstd,
+            Compression::GpgDefault,
+        ] {
+            assert_eq!(Compression::parse(c.name()).unwrap(), c);
+        }
+    }
+}
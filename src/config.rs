@@ -0,0 +1,266 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use super::errors::*;
+use super::storage::{self, Storage};
+
+// Stored alongside the bundles, so it's shared by everyone using the remote
+pub const CONFIG_NAME: &str = ".git-remote-s3.json";
+// The newest config format this version understands
+pub const CONFIG_VERSION: u32 = 1;
+pub const ENCRYPTION_GPG: &str = "gpg";
+// Bundles are stored as <root>/<ref>/<sha>.bundle
+pub const LAYOUT_REF_BUNDLES: u32 = 1;
+// Bundles are stored as <root>/bundles/<ref>/<sha>.bundle, which leaves the
+// rest of the prefix free for the remote's own objects
+pub const LAYOUT_BUNDLES_DIR: u32 = 2;
+// Heads are manifests stored as <root>/heads/<ref>/<sha>.json, listing the
+// packs under <root>/packs that hold their history (see packs.rs)
+pub const LAYOUT_PACKS: u32 = 3;
+// The newest layout this version can read and write
+pub const LAYOUT_VERSION: u32 = LAYOUT_PACKS;
+// The layout new remotes use and older ones are migrated to. Packs are
+// opted into with `admin init --packs`.
+pub const LAYOUT_DEFAULT: u32 = LAYOUT_BUNDLES_DIR;
+const BUNDLES_DIR: &str = "bundles";
+const HEADS_DIR: &str = "heads";
+// The hash algorithms of the repositories a remote can hold, as git names them
+pub const OBJECT_FORMAT_SHA1: &str = "sha1";
+pub const OBJECT_FORMAT_SHA256: &str = "sha256";
+// Shallow copies of heads, as <root>/snapshots/<ref>/<sha>.pack
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    pub version: u32,
+    pub encryption: String,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    pub layout: u32,
+    // Whether pushes also upload a snapshot of the head's latest commit, for
+    // shallow clones
+    #[serde(default)]
+    pub snapshots: bool,
+    // Every head of a remote uses the same hash algorithm, as commits of one
+    // can't build on the other's. Remotes from before sha256 support are sha1.
+    #[serde(default = "default_object_format")]
+    pub object_format: String,
+}
+
+fn default_object_format() -> String {
+    OBJECT_FORMAT_SHA1.to_string()
+}
+
+impl RemoteConfig {
+    pub fn new(recipients: Vec<String>, layout: u32) -> RemoteConfig {
+        RemoteConfig {
+            version: CONFIG_VERSION,
+            encryption: ENCRYPTION_GPG.to_string(),
+            recipients,
+            layout,
+            snapshots: false,
+            object_format: default_object_format(),
+        }
+    }
+}
+
+// The prefix that bundle keys are relative to
+pub fn bundle_root(root: &str, layout: u32) -> String {
+    match layout {
+        LAYOUT_REF_BUNDLES => root.to_string(),
+        LAYOUT_PACKS => format!("{}/{}", root, HEADS_DIR),
+        _ => format!("{}/{}", root, BUNDLES_DIR),
+    }
+}
+
+pub fn snapshot_root(root: &str) -> String {
+    format!("{}/{}", root, SNAPSHOTS_DIR)
+}
+
+// Keys that can't be a layout 1 bundle: the remote's own objects, snapshots,
+// and the bundles of later layouts while a remote is being migrated
+pub fn is_reserved(root: &storage::Key, key: &str) -> bool {
+    match key
+        .strip_prefix(&root.key)
+        .and_then(|k| k.strip_prefix('/'))
+    {
+        Some(rest) => {
+            rest.starts_with(".git-remote-s3")
+                || [BUNDLES_DIR, SNAPSHOTS_DIR]
+                    .iter()
+                    .any(|dir| rest.strip_prefix(dir).is_some_and(|r| r.starts_with('/')))
+        }
+        None => false,
+    }
+}
+
+pub fn key(root: &storage::Key) -> storage::Key {
+    storage::Key {
+        bucket: root.bucket.to_owned(),
+        key: format!("{}/{}", root.key, CONFIG_NAME),
+    }
+}
+
+// None for remotes that were never initialised, which use the defaults
+pub fn load(s3: &dyn Storage, root: &storage::Key) -> Result<Option<RemoteConfig>> {
+    let o = key(root);
+    let data = match s3.get_bytes(&o)? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    parse(&data)
+        .chain_err(|| format!("invalid remote config {}", s3.url(&o)))
+        .map(Some)
+}
+
+fn parse(data: &[u8]) -> Result<RemoteConfig> {
+    let invalid = || "couldn't parse config";
+    // Check the version first, later formats may not parse as this one
+    let value: serde_json::Value = serde_json::from_slice(data).chain_err(invalid)?;
+    let version = value
+        .get("version")
+        .and_then(|v| v.as_u64())
+        .chain_err(invalid)?;
+    if version > u64::from(CONFIG_VERSION) {
+        bail!(
+            "remote config version {} is newer than this git-remote-s3 supports ({}), please upgrade",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    let config: RemoteConfig = serde_json::from_value(value).chain_err(invalid)?;
+    if config.encryption != ENCRYPTION_GPG {
+        bail!(
+            "remote uses unsupported encryption {:?}, please upgrade",
+            config.encryption
+        );
+    }
+    if ![OBJECT_FORMAT_SHA1, OBJECT_FORMAT_SHA256].contains(&config.object_format.as_str()) {
+        bail!(
+            "remote uses unsupported object format {:?}, please upgrade",
+            config.object_format
+        );
+    }
+    if config.layout == 0 || config.layout > LAYOUT_VERSION {
+        bail!(
+            "remote uses storage layout {}, which is newer than this git-remote-s3 supports ({}), please upgrade",
+            config.layout,
+            LAYOUT_VERSION
+        );
+    }
+    Ok(config)
+}
+
+pub fn save(s3: &dyn Storage, root: &storage::Key, config: &RemoteConfig) -> Result<()> {
+    s3.put_bytes(serialize(config)?, &key(root), &HashMap::new(), None)
+}
+
+// Save the config unless the remote already has one, false if it did. It's
+// read back, as on s3 another client creating one at the same time may win.
+pub fn create(s3: &dyn Storage, root: &storage::Key, config: &RemoteConfig) -> Result<bool> {
+    if !s3.put_if_unseen(serialize(config)?, &key(root), &HashMap::new())? {
+        return Ok(false);
+    }
+    Ok(load(s3, root)?.as_ref() == Some(config))
+}
+
+fn serialize(config: &RemoteConfig) -> Result<Vec<u8>> {
+    let mut data = serde_json::to_vec_pretty(config).chain_err(|| "serialize config failed")?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trips() {
+        let config = RemoteConfig::new(vec!["a@example.com".to_string()], LAYOUT_DEFAULT);
+        let data = serde_json::to_vec(&config).unwrap();
+        assert_eq!(parse(&data).unwrap(), config);
+    }
+
+    #[test]
+    fn recipients_are_optional() {
+        let data = br#"{"version": 1, "encryption": "gpg", "layout": 1}"#;
+        let config = parse(data).unwrap();
+        assert!(config.recipients.is_empty());
+        assert!(!config.snapshots);
+        assert_eq!(config.object_format, OBJECT_FORMAT_SHA1);
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let data = br#"{"version": 2, "encryption": {"scheme": "age"}}"#;
+        let e = parse(data).unwrap_err().to_string();
+        assert!(
+            e.contains("newer than this git-remote-s3 supports"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn newer_layouts_are_refused() {
+        let data = br#"{"version": 1, "encryption": "gpg", "layout": 4}"#;
+        let e = parse(data).unwrap_err().to_string();
+        assert!(e.contains("storage layout 4"), "{}", e);
+    }
+
+    #[test]
+    fn bundle_roots() {
+        assert_eq!(bundle_root("prefix", LAYOUT_REF_BUNDLES), "prefix");
+        assert_eq!(bundle_root("prefix", LAYOUT_BUNDLES_DIR), "prefix/bundles");
+        assert_eq!(bundle_root("prefix", LAYOUT_PACKS), "prefix/heads");
+    }
+
+    #[test]
+    fn reserved_keys() {
+        let root = storage::Key {
+            bucket: "bucket".to_string(),
+            key: "prefix".to_string(),
+        };
+        assert!(is_reserved(&root, "prefix/.git-remote-s3.json"));
+        assert!(is_reserved(
+            &root,
+            "prefix/bundles/refs/heads/master/x.bundle"
+        ));
+        assert!(is_reserved(
+            &root,
+            "prefix/snapshots/refs/heads/master/x.bundle"
+        ));
+        assert!(!is_reserved(&root, "prefix/refs/heads/master/x.bundle"));
+        assert!(!is_reserved(&root, "prefix/bundlesx/y"));
+        assert!(!is_reserved(&root, "other/.git-remote-s3.json"));
+    }
+
+    #[test]
+    fn unknown_object_formats_are_refused() {
+        let data = br#"{"version": 1, "encryption": "gpg", "layout": 2, "object_format": "sha3"}"#;
+        let e = parse(data).unwrap_err().to_string();
+        assert!(e.contains("unsupported object format"), "{}", e);
+    }
+
+    #[test]
+    fn only_the_first_config_is_created() {
+        let dir = tempfile::Builder::new().prefix("config").tempdir().unwrap();
+        let root = storage::Key {
+            bucket: dir.path().to_string_lossy().to_string(),
+            key: "prefix".to_string(),
+        };
+        let s3 = crate::file::FileStorage;
+        let config = RemoteConfig::new(vec!["a@example.com".to_string()], LAYOUT_DEFAULT);
+        let other = RemoteConfig::new(vec!["b@example.com".to_string()], LAYOUT_DEFAULT);
+        assert!(create(&s3, &root, &config).unwrap());
+        assert!(!create(&s3, &root, &other).unwrap());
+        assert_eq!(load(&s3, &root).unwrap(), Some(config));
+    }
+
+    #[test]
+    fn unknown_encryption_is_refused() {
+        let data = br#"{"version": 1, "encryption": "age", "layout": 1}"#;
+        assert!(parse(data).is_err());
+    }
+}
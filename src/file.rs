@@ -0,0 +1,448 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use super::errors::*;
+use super::storage::{Download, Head, Key, Object, Storage};
+
+// Files the backend keeps next to the objects: metadata and partial writes
+const INTERNAL_PREFIX: &str = ".~";
+
+// Stores objects as files under a directory (the bucket), e.g. on a shared or
+// removable drive. Each object's metadata is kept in a hidden file beside it,
+// named after the version of the data it describes (see file_version), so
+// that it's written before the data is renamed into place and a reader gets
+// the metadata of the data it opened.
+pub struct FileStorage;
+
+// Identifies the data of an object: its inode, which a rename keeps, or where
+// there are none its size and modification time
+#[cfg(unix)]
+fn file_version(m: &fs::Metadata) -> String {
+    std::os::unix::fs::MetadataExt::ino(m).to_string()
+}
+
+#[cfg(not(unix))]
+fn file_version(m: &fs::Metadata) -> String {
+    let modified = m
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("{}-{}", m.len(), modified.as_nanos())
+}
+
+impl FileStorage {
+    fn path(&self, o: &Key) -> PathBuf {
+        Path::new(&o.bucket).join(&o.key)
+    }
+
+    fn metadata_path(&self, o: &Key, version: &str) -> PathBuf {
+        self.internal_path(o, &format!("{}.metadata", version))
+    }
+
+    // Where objects written before metadata was kept per version have it
+    fn legacy_metadata_path(&self, o: &Key) -> PathBuf {
+        self.internal_path(o, "metadata")
+    }
+
+    fn internal_path(&self, o: &Key, suffix: &str) -> PathBuf {
+        let path = self.path(o);
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        path.with_file_name(format!("{}{}.{}", INTERNAL_PREFIX, name, suffix))
+    }
+
+    // Written to a temporary file first, so readers never see part of an object
+    fn write_temp(&self, reader: &mut dyn Read, o: &Key) -> Result<PathBuf> {
+        let path = self.path(o);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("could not create directory {}", dir.display()))?;
+        }
+        let tmp = self.internal_path(o, &format!("{}.tmp", process::id()));
+        let mut f = File::create(&tmp).map_err(|e| self.io_error(e, o, "open failed"))?;
+        if let Err(e) = io::copy(reader, &mut f) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e).chain_err(|| "write failed");
+        }
+        f.sync_all().chain_err(|| "write failed")?;
+        Ok(tmp)
+    }
+
+    // Like s3, missing and unreadable objects are told apart from other errors
+    fn io_error(&self, e: io::Error, o: &Key, what: &str) -> Error {
+        match e.kind() {
+            io::ErrorKind::NotFound => Error::with_chain(e, ErrorKind::NotFound(self.url(o))),
+            io::ErrorKind::PermissionDenied => {
+                Error::with_chain(e, ErrorKind::AccessDenied(self.url(o)))
+            }
+            _ => Error::with_chain(e, what),
+        }
+    }
+
+    // The metadata of the data written to a temporary file, before it's moved
+    // into place, returning the data's version
+    fn write_metadata(
+        &self,
+        o: &Key,
+        tmp: &Path,
+        metadata: &HashMap<String, String>,
+    ) -> Result<String> {
+        let version = file_version(&fs::metadata(tmp).chain_err(|| "stat failed")?);
+        let data = serde_json::to_vec(metadata).chain_err(|| "serialize metadata failed")?;
+        let meta_tmp = self.internal_path(o, &format!("metadata.{}.tmp", process::id()));
+        fs::write(&meta_tmp, data).chain_err(|| "write failed")?;
+        fs::rename(&meta_tmp, self.metadata_path(o, &version)).chain_err(|| "rename failed")?;
+        Ok(version)
+    }
+
+    fn read_metadata(&self, o: &Key, version: &str) -> Result<Option<HashMap<String, String>>> {
+        for path in [self.metadata_path(o, version), self.legacy_metadata_path(o)].iter() {
+            match fs::read(path) {
+                Ok(data) => {
+                    return serde_json::from_slice(&data)
+                        .map(Some)
+                        .chain_err(|| "invalid metadata")
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).chain_err(|| "couldn't read metadata"),
+            }
+        }
+        Ok(None)
+    }
+
+    // Open an object along with the metadata of the data that was opened
+    fn open(&self, o: &Key) -> Result<(File, HashMap<String, String>)> {
+        loop {
+            let f =
+                File::open(self.path(o)).map_err(|e| self.io_error(e, o, "couldn't get item"))?;
+            let version = file_version(&f.metadata().chain_err(|| "stat failed")?);
+            if let Some(metadata) = self.read_metadata(o, &version)? {
+                return Ok((f, metadata));
+            }
+            // Replaced since it was opened, so its metadata is gone
+            match fs::metadata(self.path(o)) {
+                Ok(m) if file_version(&m) != version => continue,
+                _ => return Ok((f, HashMap::new())),
+            }
+        }
+    }
+
+    // Once another version of the data replaced it, the old metadata can go
+    fn remove_metadata(&self, o: &Key, version: Option<String>) -> Result<()> {
+        let mut paths = vec![self.legacy_metadata_path(o)];
+        paths.extend(version.map(|version| self.metadata_path(o, &version)));
+        for path in paths {
+            match fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(self.io_error(e, o, "Couldn't delete metadata")),
+            }
+        }
+        Ok(())
+    }
+
+    fn version(&self, o: &Key) -> Option<String> {
+        fs::metadata(self.path(o)).ok().map(|m| file_version(&m))
+    }
+}
+
+impl Storage for FileStorage {
+    fn scheme(&self) -> &'static str {
+        "file"
+    }
+
+    fn url(&self, k: &Key) -> String {
+        format!("file://{}", self.path(k).display())
+    }
+
+    fn get_reader(&self, o: &Key) -> Result<Download> {
+        let (f, metadata) = self.open(o)?;
+        Ok(Download {
+            body: Box::new(f),
+            metadata,
+        })
+    }
+
+    fn get_bytes(&self, o: &Key) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(o)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.io_error(e, o, "couldn't get item")),
+        }
+    }
+
+    fn get_head_bytes(&self, o: &Key, len: usize) -> Result<Vec<u8>> {
+        let f = File::open(self.path(o)).map_err(|e| self.io_error(e, o, "couldn't get item"))?;
+        let mut contents = Vec::new();
+        f.take(len as u64)
+            .read_to_end(&mut contents)
+            .chain_err(|| "read failed")?;
+        Ok(contents)
+    }
+
+    fn put_bytes(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        self.put_reader(&mut &contents[..], o, metadata, storage_class)
+    }
+
+    fn put_reader(
+        &self,
+        reader: &mut dyn Read,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        _storage_class: Option<&str>,
+    ) -> Result<()> {
+        let tmp = self.write_temp(reader, o)?;
+        let version = self.write_metadata(o, &tmp, metadata)?;
+        let replaced = self.version(o).filter(|old| *old != version);
+        fs::rename(&tmp, self.path(o)).chain_err(|| "rename failed")?;
+        self.remove_metadata(o, replaced)
+    }
+
+    fn put_if_unseen(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+    ) -> Result<bool> {
+        let tmp = self.write_temp(&mut &contents[..], o)?;
+        let version = self.write_metadata(o, &tmp, metadata)?;
+        // Unlike a rename, linking fails if the object exists
+        let linked = fs::hard_link(&tmp, self.path(o));
+        fs::remove_file(&tmp).chain_err(|| "remove failed")?;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                fs::remove_file(self.metadata_path(o, &version)).chain_err(|| "remove failed")?;
+                Ok(false)
+            }
+            Err(e) => Err(e).chain_err(|| "link failed"),
+        }
+    }
+
+    fn head(&self, o: &Key) -> Result<Head> {
+        if !self.path(o).is_file() {
+            bail!(ErrorKind::NotFound(self.url(o)));
+        }
+        let (_, metadata) = self.open(o)?;
+        Ok(Head {
+            metadata,
+            storage_class: None,
+        })
+    }
+
+    fn copy(
+        &self,
+        from: &Key,
+        to: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let contents =
+            fs::read(self.path(from)).map_err(|e| self.io_error(e, from, "couldn't get item"))?;
+        self.put_bytes(contents, to, metadata, storage_class)
+    }
+
+    fn del(&self, o: &Key) -> Result<()> {
+        let version = self.version(o);
+        match fs::remove_file(self.path(o)) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(self.io_error(e, o, "Couldn't delete object")),
+        }
+        self.remove_metadata(o, version)?;
+        // Tidy up directories left empty, as there are no directories in s3
+        let bucket = Path::new(&o.bucket);
+        let mut dir = self.path(o).parent().map(Path::to_path_buf);
+        while let Some(d) = dir {
+            if d == bucket || fs::remove_dir(&d).is_err() {
+                break;
+            }
+            dir = d.parent().map(Path::to_path_buf);
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: &Key) -> Result<Vec<Object>> {
+        // Everything under the directory part of the prefix, then filtered
+        let dir = match prefix.key.rfind('/') {
+            Some(idx) => &prefix.key[..idx],
+            None => "",
+        };
+        let mut objects = vec![];
+        let mut todo = vec![Path::new(&prefix.bucket).join(dir)];
+        while let Some(dir) = todo.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).chain_err(|| "Couldn't list directory"),
+            };
+            for entry in entries {
+                let entry = entry.chain_err(|| "Couldn't list directory")?;
+                let path = entry.path();
+                let file_type = entry.file_type().chain_err(|| "Couldn't list directory")?;
+                if file_type.is_dir() {
+                    todo.push(path);
+                    continue;
+                }
+                if entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(INTERNAL_PREFIX)
+                {
+                    continue;
+                }
+                let key = match path.strip_prefix(&prefix.bucket) {
+                    Ok(key) => key
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    Err(_) => continue,
+                };
+                if !key.starts_with(&prefix.key) {
+                    continue;
+                }
+                let metadata = entry.metadata().chain_err(|| "Couldn't list directory")?;
+                let modified: DateTime<Utc> = metadata
+                    .modified()
+                    .chain_err(|| "Couldn't list directory")?
+                    .into();
+                objects.push(Object {
+                    key,
+                    last_modified: modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    size: metadata.len() as i64,
+                });
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    #[test]
+    fn objects_round_trip() {
+        let dir = Builder::new().prefix("file_storage").tempdir().unwrap();
+        let storage = FileStorage;
+        let key = |k: &str| Key {
+            bucket: dir.path().to_string_lossy().to_string(),
+            key: k.to_string(),
+        };
+        let mut metadata = HashMap::new();
+        metadata.insert("pushed-at".to_string(), "now".to_string());
+
+        storage
+            .put_bytes(
+                b"bundle".to_vec(),
+                &key("repo/refs/heads/master/a.bundle"),
+                &metadata,
+                None,
+            )
+            .unwrap();
+        assert!(!storage
+            .put_if_unseen(
+                b"other".to_vec(),
+                &key("repo/refs/heads/master/a.bundle"),
+                &metadata
+            )
+            .unwrap());
+        assert!(storage
+            .put_if_unseen(b"config".to_vec(), &key("repo/.config"), &HashMap::new())
+            .unwrap());
+        storage
+            .put_bytes(b"x".to_vec(), &key("repository/b"), &HashMap::new(), None)
+            .unwrap();
+
+        let listed = storage.list(&key("repo/")).unwrap();
+        let keys: Vec<_> = listed.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["repo/.config", "repo/refs/heads/master/a.bundle"]
+        );
+        assert_eq!(listed[1].size, 6);
+        assert_eq!(
+            storage
+                .get_bytes(&key("repo/refs/heads/master/a.bundle"))
+                .unwrap(),
+            Some(b"bundle".to_vec())
+        );
+        assert_eq!(
+            storage
+                .head(&key("repo/refs/heads/master/a.bundle"))
+                .unwrap()
+                .metadata,
+            metadata
+        );
+        assert_eq!(storage.get_bytes(&key("repo/missing")).unwrap(), None);
+
+        storage
+            .del(&key("repo/refs/heads/master/a.bundle"))
+            .unwrap();
+        assert!(!dir.path().join("repo/refs").exists());
+        assert_eq!(storage.list(&key("repo/")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn overwrites_change_data_and_metadata_together() {
+        let dir = Builder::new().prefix("file_storage").tempdir().unwrap();
+        let o = Key {
+            bucket: dir.path().to_string_lossy().to_string(),
+            key: "repo/a.bundle".to_string(),
+        };
+        let put = |version: usize| {
+            let mut metadata = HashMap::new();
+            metadata.insert("version".to_string(), version.to_string());
+            FileStorage
+                .put_bytes(version.to_string().into_bytes(), &o, &metadata, None)
+                .unwrap();
+        };
+        let check = || {
+            let download = FileStorage.get_reader(&o).unwrap();
+            let mut body = String::new();
+            { download.body }.read_to_string(&mut body).unwrap();
+            assert_eq!(Some(&body), download.metadata.get("version"));
+        };
+        put(0);
+
+        // Interrupted after the new metadata was written
+        let mut metadata = HashMap::new();
+        metadata.insert("version".to_string(), "1".to_string());
+        let tmp = FileStorage.write_temp(&mut &b"1"[..], &o).unwrap();
+        let version = FileStorage.write_metadata(&o, &tmp, &metadata).unwrap();
+        check();
+        fs::remove_file(tmp).unwrap();
+        fs::remove_file(FileStorage.metadata_path(&o, &version)).unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| (1..200).for_each(put));
+            for _ in 0..200 {
+                check();
+            }
+        });
+        check();
+        // Only the metadata of the latest data is left
+        let internal = fs::read_dir(dir.path().join("repo"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name() != "a.bundle")
+            .count();
+        assert_eq!(internal, 1);
+    }
+}
@@ -1,37 +1,189 @@
+use itertools::Itertools;
+
 use super::errors::*;
-use std::path::Path;
-use std::process::Command;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
 
-pub fn bundle_create(bundle: &Path, ref_name: &str) -> Result<()> {
-    let result = Command::new("git")
-        .arg("bundle")
+thread_local! {
+    // The repository git commands run in, when it's not the one in $GIT_DIR
+    static GIT_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// Until it's dropped, git commands on this thread run in another repository,
+// rather than changing the environment of the whole process
+pub struct UseDir {
+    previous: Option<PathBuf>,
+}
+
+// The directory needs to be absolute, as git commands may run elsewhere
+pub fn use_dir(dir: &Path) -> UseDir {
+    UseDir {
+        previous: GIT_DIR.with(|d| d.replace(Some(dir.to_path_buf()))),
+    }
+}
+
+impl Drop for UseDir {
+    fn drop(&mut self) {
+        GIT_DIR.with(|d| *d.borrow_mut() = self.previous.take());
+    }
+}
+
+// The repository in use on this thread, for work handed to other threads
+pub fn current_dir() -> Option<PathBuf> {
+    GIT_DIR.with(|d| d.borrow().clone())
+}
+
+fn git() -> Command {
+    let mut cmd = Command::new("git");
+    if let Some(dir) = current_dir() {
+        cmd.env("GIT_DIR", dir);
+    }
+    cmd
+}
+
+// Writes the bundle to stdout, leaving out the history of `exclude`
+pub fn bundle_create_command(ref_name: &str, exclude: &[String]) -> Command {
+    let mut cmd = git();
+    cmd.arg("bundle")
         .arg("create")
+        .arg("-q")
+        .arg("-")
+        .arg(ref_name);
+    for sha in exclude {
+        cmd.arg(format!("^{}", sha));
+    }
+    cmd
+}
+
+// Writes a pack of just the commits read from stdin and their trees. The
+// shallow file lists them, so git doesn't walk their parents.
+pub fn snapshot_pack_command(shallow_file: &Path) -> Command {
+    let mut cmd = git();
+    cmd.arg("--shallow-file")
+        .arg(shallow_file)
+        .arg("pack-objects")
+        .arg("-q")
+        .arg("--stdout")
+        .arg("--revs");
+    cmd
+}
+
+// Stores the pack read from stdin in the repository
+pub fn index_pack_command() -> Command {
+    let mut cmd = git();
+    cmd.arg("index-pack").arg("--stdin");
+    cmd
+}
+
+// Reads the bundle from stdin, which git reads a byte at a time up to the
+// pack so that it can be a pipe. Every ref is read if `ref_name` is None.
+pub fn bundle_unbundle_command(ref_name: Option<&str>) -> Command {
+    let mut cmd = git();
+    cmd.arg("bundle").arg("unbundle").arg("/dev/stdin");
+    cmd.args(ref_name);
+    cmd
+}
+
+pub fn bundle_unbundle(bundle: &Path, ref_name: &str) -> Result<()> {
+    let result = git()
+        .arg("bundle")
+        .arg("unbundle")
         .arg(bundle.to_str().chain_err(|| "bundle path invalid")?)
         .arg(ref_name)
         .output()
         .chain_err(|| "failed to run git")?;
     if !result.status.success() {
-        bail!("git bundle failed");
+        bail!("git unbundle failed");
     }
     Ok(())
 }
 
-pub fn bundle_unbundle(bundle: &Path, ref_name: &str) -> Result<()> {
+// git's default hash algorithm is used if `object_format` is None
+pub fn init_bare(dir: &Path, object_format: Option<&str>) -> Result<()> {
     let result = Command::new("git")
+        .arg("init")
+        .arg("--bare")
+        .arg("-q")
+        .args(object_format.map(|f| format!("--object-format={}", f)))
+        .arg(dir.to_str().chain_err(|| "repo path invalid")?)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git init failed");
+    }
+    Ok(())
+}
+
+pub fn bundle_verify(bundle: &Path) -> Result<bool> {
+    let result = git()
         .arg("bundle")
-        .arg("unbundle")
+        .arg("verify")
+        .arg("-q")
+        .arg(bundle.to_str().chain_err(|| "bundle path invalid")?)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    Ok(result.status.success())
+}
+
+// The (sha, ref name) pairs a bundle contains
+pub fn bundle_list_heads(bundle: &Path) -> Result<Vec<(String, String)>> {
+    let result = git()
+        .arg("bundle")
+        .arg("list-heads")
         .arg(bundle.to_str().chain_err(|| "bundle path invalid")?)
-        .arg(ref_name)
         .output()
         .chain_err(|| "failed to run git")?;
     if !result.status.success() {
-        bail!("git unbundle failed");
+        bail!("git bundle list-heads failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(s.lines()
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ' ');
+            Some((split.next()?.to_string(), split.next()?.to_string()))
+        })
+        .collect())
+}
+
+pub fn update_ref(name: &str, sha: &str) -> Result<()> {
+    let result = git()
+        .arg("update-ref")
+        .arg(name)
+        .arg(sha)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git update-ref failed");
     }
     Ok(())
 }
 
+// The (sha, ref name) pairs of every ref in the repository
+pub fn for_each_ref() -> Result<Vec<(String, String)>> {
+    let result = git()
+        .arg("for-each-ref")
+        .arg("--format=%(objectname) %(refname)")
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git for-each-ref failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(s.lines()
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ' ');
+            Some((split.next()?.to_string(), split.next()?.to_string()))
+        })
+        .collect())
+}
+
 pub fn is_ancestor(base_ref: &str, remote_ref: &str) -> Result<bool> {
-    let result = Command::new("git")
+    let result = git()
         .arg("merge-base")
         .arg("--is-ancestor")
         .arg(remote_ref)
@@ -41,8 +193,154 @@ pub fn is_ancestor(base_ref: &str, remote_ref: &str) -> Result<bool> {
     Ok(result.status.success())
 }
 
+// The candidates that are sha or its ancestors
+pub fn ancestors_among(sha: &str, candidates: &HashSet<&str>) -> Result<Vec<String>> {
+    if candidates.is_empty() {
+        return Ok(vec![]);
+    }
+    let result = git()
+        .arg("rev-list")
+        .arg(sha)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git rev-list failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(s.lines()
+        .filter(|c| candidates.contains(c))
+        .map(|c| c.to_string())
+        .collect())
+}
+
+// The commits that aren't ancestors of any of the others
+pub fn independent(shas: &[String]) -> Result<Vec<String>> {
+    if shas.len() < 2 {
+        return Ok(shas.to_vec());
+    }
+    let result = git()
+        .arg("merge-base")
+        .arg("--independent")
+        .args(shas)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git merge-base failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(s.lines().map(|l| l.to_string()).collect())
+}
+
+// The commits the repository doesn't have
+pub fn missing_commits(shas: &[&str]) -> Result<HashSet<String>> {
+    let mut child = git()
+        .arg("cat-file")
+        .arg("--batch-check")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .chain_err(|| "failed to run git")?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = shas.iter().map(|s| format!("{}^{{commit}}\n", s)).join("");
+    // Written on another thread, so that git doesn't block writing its output
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let result = child.wait_with_output().chain_err(|| "failed to run git")?;
+    writer
+        .join()
+        .map_err(|_| "writer thread panicked")?
+        .chain_err(|| "couldn't write to git")?;
+    if !result.status.success() {
+        bail!("git cat-file failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(s.lines()
+        .filter_map(|line| line.strip_suffix("^{commit} missing"))
+        .map(|sha| sha.to_string())
+        .collect())
+}
+
+// The file listing the commits of a shallow repository whose parents it
+// doesn't have
+fn shallow_file() -> Result<PathBuf> {
+    let result = git()
+        .arg("rev-parse")
+        .arg("--git-path")
+        .arg("shallow")
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git rev-parse failed");
+    }
+    let s = String::from_utf8(result.stdout).chain_err(|| "not utf8")?;
+    Ok(PathBuf::from(s.trim()))
+}
+
+pub fn shallow_commits() -> Result<HashSet<String>> {
+    match fs::read_to_string(shallow_file()?) {
+        Ok(s) => Ok(s.lines().map(|l| l.to_string()).collect()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e).chain_err(|| "couldn't read shallow file"),
+    }
+}
+
+fn write_shallow(commits: &HashSet<String>) -> Result<()> {
+    let path = shallow_file()?;
+    if commits.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            removed => removed.chain_err(|| "couldn't remove shallow file"),
+        };
+    }
+    let data = commits.iter().sorted().map(|c| format!("{}\n", c)).join("");
+    fs::write(&path, data).chain_err(|| "couldn't write shallow file")
+}
+
+// Make the repository shallow at these commits, whose parents it doesn't have
+pub fn add_shallow(shas: &[&str]) -> Result<()> {
+    let mut commits = shallow_commits()?;
+    commits.extend(shas.iter().map(|s| s.to_string()));
+    write_shallow(&commits)
+}
+
+// Stop treating commits as shallow once all of their parents have been
+// fetched
+pub fn unshallow_complete() -> Result<()> {
+    let shallow = shallow_commits()?;
+    let mut remaining = HashSet::new();
+    for sha in shallow.iter() {
+        let parents = commit_parents(sha)?;
+        let parents = parents.iter().map(String::as_str).collect_vec();
+        if !missing_commits(&parents)?.is_empty() {
+            remaining.insert(sha.to_owned());
+        }
+    }
+    if remaining.len() < shallow.len() {
+        write_shallow(&remaining)?;
+    }
+    Ok(())
+}
+
+// Read from the commit itself, as git hides the parents of shallow commits
+fn commit_parents(sha: &str) -> Result<Vec<String>> {
+    let result = git()
+        .arg("cat-file")
+        .arg("commit")
+        .arg(sha)
+        .output()
+        .chain_err(|| "failed to run git")?;
+    if !result.status.success() {
+        bail!("git cat-file failed");
+    }
+    let s = String::from_utf8_lossy(&result.stdout);
+    Ok(s.lines()
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.strip_prefix("parent "))
+        .map(|p| p.to_string())
+        .collect())
+}
+
 pub fn config(setting: &str) -> Result<String> {
-    let result = Command::new("git")
+    let result = git()
         .arg("config")
         .arg(setting)
         .output()
@@ -54,8 +352,13 @@ pub fn config(setting: &str) -> Result<String> {
     Ok(s.trim().to_string())
 }
 
+// The hash algorithm of the repository's objects, e.g. sha1 or sha256
+pub fn object_format() -> Result<String> {
+    rev_parse("--show-object-format")
+}
+
 pub fn rev_parse(rev: &str) -> Result<String> {
-    let result = Command::new("git")
+    let result = git()
         .arg("rev-parse")
         .arg(rev)
         .output()
@@ -1,48 +1,206 @@
 use super::errors::*;
+use std::collections::HashMap;
+use std::io::Write;
 use std::path::Path;
 use std::process::Command;
-use std::io::{Write};
 
-pub fn encrypt(recipients: &[String], i: &Path, o: &Path) -> Result<()> {
+// Encrypts stdin to stdout, compressing it first unless `compress` is false
+pub fn encrypt_command(recipients: &[String], compress: bool) -> Command {
     let mut cmd = Command::new("gpg");
     cmd.arg("-q").arg("--batch");
+    if !compress {
+        cmd.arg("--compress-algo").arg("none");
+    }
     for recipient in recipients {
         cmd.arg("-r").arg(recipient);
     }
+    cmd.arg("-e");
+    cmd
+}
+
+// Decrypts stdin to stdout
+pub fn decrypt_command() -> Command {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("-q").arg("--batch").arg("-d");
     cmd
-        .arg("-o")
+}
+
+pub fn encrypt(recipients: &[String], i: &Path, o: &Path) -> Result<()> {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("-q").arg("--batch");
+    for recipient in recipients {
+        cmd.arg("-r").arg(recipient);
+    }
+    cmd.arg("-o")
         .arg(o.to_str().chain_err(|| "out path invalid")?)
         .arg("-e")
         .arg(i.to_str().chain_err(|| "in path invalid")?);
-    let result = cmd
-        .output()
-        .chain_err(|| "failed to run gpg encrypt")?;
+    let result = cmd.output().chain_err(|| "failed to run gpg encrypt")?;
     if !result.status.success() {
-        write!(std::io::stdout(), "Failed command: {:?}", cmd).unwrap();
-        std::io::stdout().write_all(&result.stdout).unwrap();
+        eprintln!("Failed command: {:?}", cmd);
+        std::io::stderr().write_all(&result.stdout).unwrap();
         std::io::stderr().write_all(&result.stderr).unwrap();
-        bail!("gpg encrypt failed");
+        bail!(ErrorKind::Crypto("encrypt".to_string()));
     }
     Ok(())
 }
 
-pub fn decrypt(i: &Path, o: &Path) -> Result<()> {
+// The key ids an encrypted message's session key was encrypted to, read from
+// the public-key encrypted session key packets at its start (RFC 4880 5.1).
+// A key id of all zeros means the recipient was hidden with --throw-keyids.
+pub fn recipient_key_ids(data: &[u8]) -> Result<Vec<String>> {
+    let mut key_ids = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (tag, header_len, body_len) = packet_header(&data[pos..])?;
+        let body = data
+            .get((pos + header_len)..(pos + header_len + body_len))
+            .chain_err(|| "truncated packet")?;
+        match tag {
+            // Public-Key Encrypted Session Key
+            1 => {
+                if body.first() != Some(&3) {
+                    bail!("unsupported session key packet version");
+                }
+                let key_id = body.get(1..9).chain_err(|| "truncated packet")?;
+                key_ids.push(key_id.iter().map(|b| format!("{:02X}", b)).collect());
+            }
+            // Symmetric-Key Encrypted Session Key (a passphrase)
+            3 => {}
+            // Anything else is the encrypted data itself
+            _ => return Ok(key_ids),
+        }
+        pos += header_len + body_len;
+    }
+    bail!("no encrypted data found")
+}
+
+// (tag, header length, body length) of the packet at the start of data
+fn packet_header(data: &[u8]) -> Result<(u8, usize, usize)> {
+    let byte = |i: usize| -> Result<usize> {
+        data.get(i)
+            .map(|b| *b as usize)
+            .chain_err(|| "truncated packet header")
+    };
+    let first = byte(0)?;
+    if first & 0x80 == 0 {
+        bail!("not an OpenPGP packet");
+    }
+    if first & 0x40 == 0 {
+        // Old format
+        let tag = ((first >> 2) & 0x0f) as u8;
+        match first & 0x03 {
+            0 => Ok((tag, 2, byte(1)?)),
+            1 => Ok((tag, 3, (byte(1)? << 8) | byte(2)?)),
+            2 => Ok((
+                tag,
+                5,
+                (byte(1)? << 24) | (byte(2)? << 16) | (byte(3)? << 8) | byte(4)?,
+            )),
+            _ => Ok((tag, 1, 0)),
+        }
+    } else {
+        // New format, where partial lengths are only used by data packets
+        let tag = (first & 0x3f) as u8;
+        match byte(1)? {
+            len @ 0..=191 => Ok((tag, 2, len)),
+            len @ 192..=223 => Ok((tag, 3, ((len - 192) << 8) + byte(2)? + 192)),
+            255 => Ok((
+                tag,
+                6,
+                (byte(2)? << 24) | (byte(3)? << 16) | (byte(4)? << 8) | byte(5)?,
+            )),
+            _ => Ok((tag, 2, 0)),
+        }
+    }
+}
+
+// Encryption capable key ids (of primary keys and subkeys), mapped to the
+// user id of their key. Lists secret keys, or public keys matching `names`
+// (all of them if empty).
+pub fn encryption_keys(secret: bool, names: &[String]) -> Result<HashMap<String, String>> {
     let mut cmd = Command::new("gpg");
-    cmd
-        .arg("-q")
-        .arg("--batch")
-        .arg("-o")
-        .arg(o.to_str().chain_err(|| "out path invalid")?)
-        .arg("-d")
-        .arg(i.to_str().chain_err(|| "in path invalid")?);
-    let result = cmd
-        .output()
-        .chain_err(|| "failed to run gpg decrypt")?;
+    cmd.arg("--batch").arg("--with-colons");
+    if secret {
+        cmd.arg("--list-secret-keys");
+    } else {
+        cmd.arg("--list-keys");
+    }
+    let result = cmd.args(names).output().chain_err(|| "failed to run gpg")?;
     if !result.status.success() {
-        write!(std::io::stdout(), "Failed command: {:?}", cmd).unwrap();
-        std::io::stdout().write_all(&result.stdout).unwrap();
-        std::io::stderr().write_all(&result.stderr).unwrap();
-        bail!("gpg decrypt failed");
+        bail!("gpg could not list keys {}", names.join(" "));
+    }
+    let listing = String::from_utf8_lossy(&result.stdout);
+
+    let mut keys = HashMap::new();
+    let mut key_ids: Vec<String> = vec![];
+    let mut user_id = String::new();
+    let mut finish = |key_ids: &mut Vec<String>, user_id: &str| {
+        for key_id in key_ids.drain(..) {
+            keys.insert(key_id, user_id.to_string());
+        }
+    };
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or("");
+        match field(0) {
+            "pub" | "sec" => {
+                finish(&mut key_ids, &user_id);
+                user_id = String::new();
+            }
+            "uid" if user_id.is_empty() => user_id = field(9).to_string(),
+            _ => {}
+        }
+        // Lower case capabilities are those of this key, rather than the whole key
+        if matches!(field(0), "pub" | "sub" | "sec" | "ssb") && field(11).contains('e') {
+            key_ids.push(field(4).to_string());
+        }
+    }
+    finish(&mut key_ids, &user_id);
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkesk(key_id: [u8; 8]) -> Vec<u8> {
+        // version, key id, algorithm (RSA) and a tiny MPI
+        let mut body = vec![3];
+        body.extend_from_slice(&key_id);
+        body.extend_from_slice(&[1, 0, 8, 0xff]);
+        let mut packet = vec![0xc1, body.len() as u8];
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn reads_session_key_recipients() {
+        let mut data = pkesk([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        data.extend(pkesk([0; 8]));
+        // old format encrypted data packet, with a two octet length
+        data.extend_from_slice(&[0x80 | (18 << 2) | 1, 0, 3, 1, 2, 3]);
+        assert_eq!(
+            recipient_key_ids(&data).unwrap(),
+            vec!["0123456789ABCDEF", "0000000000000000"]
+        );
+    }
+
+    #[test]
+    fn reads_old_format_and_partial_data_packets() {
+        let mut old = pkesk([0xff; 8]);
+        old[0] = 0x80 | (1 << 2);
+        // new format encrypted data packet with a partial length
+        old.extend_from_slice(&[0xc0 | 18, 0xe1, 1, 2]);
+        assert_eq!(recipient_key_ids(&old).unwrap(), vec!["FFFFFFFFFFFFFFFF"]);
+    }
+
+    #[test]
+    fn rejects_non_openpgp_data() {
+        assert!(recipient_key_ids(b"# v2 git bundle").is_err());
+        assert!(recipient_key_ids(&[]).is_err());
+        let mut truncated = pkesk([1; 8]);
+        truncated.truncate(6);
+        assert!(recipient_key_ids(&truncated).is_err());
     }
-    Ok(())
 }
@@ -0,0 +1,1240 @@
+#![recursion_limit = "1024"]
+#[macro_use]
+extern crate error_chain;
+extern crate itertools;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+
+use rusoto_core::Region;
+use rusoto_s3::S3Client;
+
+use chrono::{SecondsFormat, Utc};
+use itertools::Itertools;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Once;
+
+use tempfile::Builder;
+
+pub mod errors {
+    error_chain! {
+        errors {
+            AccessDenied(url: String) {
+                description("access denied")
+                display("access denied to {}", url)
+            }
+            NotFound(url: String) {
+                description("not found")
+                display("{} not found", url)
+            }
+            Crypto(operation: String) {
+                description("gpg failed")
+                display("gpg {} failed", operation)
+            }
+            NonFastForward(name: String) {
+                description("non-fast-forward")
+                display("{} has changed on the remote, fetch and merge it or force push to add a new head", name)
+            }
+            StaleLease(name: String) {
+                description("stale info")
+                display("{} is not the head git expected (--force-with-lease)", name)
+            }
+            LockContention(name: String) {
+                description("concurrent push")
+                display("another client pushed {} at the same time, both heads were kept", name)
+            }
+        }
+    }
+
+    impl Error {
+        // The first typed error in the chain, as the errors that are told apart
+        // are often wrapped with more context
+        pub fn typed_kind(&self) -> Option<&ErrorKind> {
+            let mut error = Some(self);
+            while let Some(e) = error {
+                if !matches!(e.kind(), ErrorKind::Msg(_)) {
+                    return Some(e.kind());
+                }
+                error = e.1.next_error.as_ref().and_then(|n| n.downcast_ref());
+            }
+            None
+        }
+    }
+}
+use errors::*;
+pub mod admin;
+mod compress;
+mod config;
+mod file;
+mod git;
+mod gpg;
+mod packs;
+mod pipe;
+mod s3;
+pub mod storage;
+
+use compress::Compression;
+use pipe::{Pipeline, Stage};
+use storage::Storage;
+
+struct Settings {
+    //git_dir: PathBuf,
+    remote_alias: String,
+    //remote_url: String,
+    root: storage::Key,
+    // The remote's shared config, if it has been initialised
+    config: Option<config::RemoteConfig>,
+    // Where warnings for the user go, see Config
+    warn: Option<fn(&str)>,
+    // Keys that don't belong to the layout are only reported once
+    warned_ignored: Once,
+}
+
+impl Settings {
+    fn warn(&self, message: &str) {
+        if let Some(warn) = self.warn {
+            warn(message);
+        }
+    }
+
+    // Remotes without a config predate layouts, and use the first
+    fn layout(&self) -> u32 {
+        self.config
+            .as_ref()
+            .map_or(config::LAYOUT_REF_BUNDLES, |c| c.layout)
+    }
+
+    fn bundle_root(&self) -> String {
+        config::bundle_root(&self.root.key, self.layout())
+    }
+
+    // Keys under the bundle root that hold something other than bundles
+    fn is_reserved(&self, key: &str) -> bool {
+        self.layout() == config::LAYOUT_REF_BUNDLES && config::is_reserved(&self.root, key)
+    }
+
+    // Heads are bundles, except for remotes using packs
+    fn head_extension(&self) -> &'static str {
+        match self.layout() {
+            config::LAYOUT_PACKS => "json",
+            _ => "bundle",
+        }
+    }
+
+    fn snapshots(&self) -> bool {
+        self.config.as_ref().is_some_and(|c| c.snapshots)
+    }
+
+    fn snapshot_key(&self, r: &GitRef) -> storage::Key {
+        storage::Key {
+            bucket: self.root.bucket.to_owned(),
+            key: r.bundle_path(config::snapshot_root(&self.root.key), "pack"),
+        }
+    }
+
+    // The prefix listing what's under a directory of the bucket. The trailing
+    // slash keeps other prefixes that start with the same name out.
+    fn dir_prefix(&self, dir: &str) -> storage::Key {
+        storage::Key {
+            bucket: self.root.bucket.to_owned(),
+            key: format!("{}/", dir),
+        }
+    }
+
+    fn bundle_key(&self, r: &GitRef) -> storage::Key {
+        storage::Key {
+            bucket: self.root.bucket.to_owned(),
+            key: r.bundle_path(self.bundle_root(), self.head_extension()),
+        }
+    }
+}
+
+pub(crate) fn s3_region() -> Region {
+    if let Ok(endpoint) = env::var("S3_ENDPOINT") {
+        Region::Custom {
+            name: String::from("us-east-1"),
+            endpoint,
+        }
+    } else {
+        Region::default()
+    }
+}
+
+pub(crate) fn s3_client() -> S3Client {
+    S3Client::new(s3_region())
+}
+
+// The storage a remote url points at, the client is used if it's on s3
+fn open_url(url: &str, s3: S3Client) -> Result<(Box<dyn Storage>, storage::Key)> {
+    if url.starts_with("file://") {
+        return Ok((Box::new(file::FileStorage), parse_file_url(url)?));
+    }
+    Ok((Box::new(s3::S3Storage::new(s3)), parse_url(url)?))
+}
+
+// file:///dir/prefix stores objects under /dir, like a bucket
+fn parse_file_url(url: &str) -> Result<storage::Key> {
+    let path = url["file://".len()..].trim_end_matches('/');
+    if !path.starts_with('/') {
+        bail!("file url is not absolute. expected a url in the format file:///path/to/dir");
+    }
+    let slash = path.rfind('/').unwrap();
+    let (bucket, prefix) = (&path[..slash], &path[(slash + 1)..]);
+    if prefix.is_empty() {
+        bail!("file url does not appear to have a directory. expected a url in the format file:///path/to/dir");
+    }
+    Ok(storage::Key {
+        bucket: if bucket.is_empty() { "/" } else { bucket }.to_string(),
+        key: prefix.to_string(),
+    })
+}
+
+fn parse_url(url: &str) -> Result<storage::Key> {
+    let (bucket, path) = {
+        if !url.starts_with("s3://") {
+            bail!("remote url does not start with s3://. expected a url in the format s3://bucket/prefix")
+        }
+        let url = &url[5..];
+        let slash = match url.find('/') {
+            Some(idx) => idx,
+            None => {
+                bail!("remote url does not appear to have a prefix. expected a url in the format s3://bucket/prefix");
+            }
+        };
+        let bucket = &url[..slash];
+        let path = &url[(slash + 1)..];
+        let path = path.strip_suffix('/').unwrap_or(path);
+        if path.is_empty() {
+            bail!("remote url does not appear to have a prefix. expected a url in the format s3://bucket/prefix");
+        }
+        (bucket, path)
+    };
+    Ok(storage::Key {
+        bucket: bucket.to_string(),
+        key: path.to_string(),
+    })
+}
+
+// How to open a remote
+#[derive(Default)]
+pub struct Config {
+    // The name of the git remote, whose `remote.<alias>.*` settings are used
+    pub alias: String,
+    // The client for s3 urls, by default one for S3_ENDPOINT or the default
+    // region
+    pub s3_client: Option<S3Client>,
+    // Called with each warning for the user, such as stale heads or stray
+    // keys. Without it warnings are dropped
+    pub warn: Option<fn(&str)>,
+}
+
+// A remote's heads and the operations git uses on them, for the helper and
+// other tools
+pub struct Remote {
+    s3: Box<dyn Storage>,
+    settings: Settings,
+}
+
+impl Remote {
+    // Fails if this clone's recipients disagree with the remote's config
+    pub fn open(url: &str, config: Config) -> Result<Remote> {
+        let client = config.s3_client.unwrap_or_else(s3_client);
+        let (s3, root) = open_url(url, client)?;
+        let settings = Settings {
+            remote_alias: config.alias,
+            config: config::load(&*s3, &root)?,
+            root,
+            warn: config.warn,
+            warned_ignored: Once::new(),
+        };
+        configured_recipients(&settings)?;
+        Ok(Remote { s3, settings })
+    }
+
+    // The heads of every ref, by ref name
+    pub fn list_refs(&self) -> Result<HashMap<String, RemoteRefs>> {
+        list_remote_refs(&*self.s3, &self.settings)
+    }
+
+    // The hash algorithm of the remote's heads, given its refs. None for an
+    // empty remote that doesn't record one, which takes the first push's.
+    pub fn object_format(&self, refs: &HashMap<String, RemoteRefs>) -> Option<String> {
+        object_format(&self.settings, refs)
+    }
+
+    // Fails unless the current repository uses the remote's hash algorithm,
+    // as a sha1 history can't be mixed with a sha256 one
+    pub fn check_object_format(&self, refs: &HashMap<String, RemoteRefs>) -> Result<()> {
+        let local_format = git::object_format()?;
+        match self.object_format(refs) {
+            Some(format) if format != local_format => bail!(
+                "{} holds {} repositories, and this one is {}",
+                self.s3.url(&self.settings.root),
+                format,
+                local_format
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    // Download a head into the current repository. The name is the ref's,
+    // rather than the `<ref>__<sha>` a stale head is listed as.
+    pub fn fetch(&self, r: &GitRef) -> Result<()> {
+        fetch_from_s3(&*self.s3, &self.settings, r)?;
+        // Fetching into a shallow repository may have completed its history
+        git::unshallow_complete()
+    }
+
+    // Download just the latest commit of a head, for a clone or fetch with
+    // --depth=1, if the remote keeps snapshots. Otherwise, or when more
+    // history is asked for, the whole head is fetched.
+    pub fn fetch_shallow(&self, r: &GitRef, depth: u32) -> Result<()> {
+        let s3 = &*self.s3;
+        if depth == 1 && self.settings.snapshots() {
+            // git asks for a head once per ref pointing at it, e.g. HEAD
+            if !git::missing_commits(&[&r.sha])?.contains(&r.sha) {
+                return Ok(());
+            }
+            let o = self.settings.snapshot_key(r);
+            match index_object(s3, &o) {
+                Ok(()) => return git::add_shallow(&[&r.sha]),
+                // Pushed by a client that doesn't upload snapshots
+                Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.fetch(r)
+    }
+
+    // Push a local ref as the new head of the same ref on the remote. A push
+    // that is refused fails with NonFastForward or StaleLease, without
+    // changing the remote.
+    pub fn push(&self, name: &str, force: bool, push_options: &PushOptions) -> Result<()> {
+        let s3 = &*self.s3;
+        let settings = &self.settings;
+        let all_remote_refs = self.list_refs()?;
+        self.check_object_format(&all_remote_refs)?;
+        let remote_refs = all_remote_refs.get(name);
+        let prev_ref = remote_refs.map(|rs| rs.latest_ref());
+        let local_sha = git::rev_parse(name)?;
+        let local_ref = GitRef {
+            name: name.to_string(),
+            sha: local_sha,
+        };
+
+        // A lease (--force-with-lease) only allows the push if the remote head is
+        // still the one git last saw, and then behaves like a force push.
+        if let Some(expected) = &push_options.lease {
+            let current = prev_ref.map(|r| r.reference.sha.as_str());
+            if !lease_matches(expected, current) {
+                bail!(ErrorKind::StaleLease(name.to_string()));
+            }
+        }
+        let force = force || push_options.lease.is_some();
+
+        if let Some(prev_ref) = prev_ref {
+            if !force && !git::is_ancestor(&local_ref.sha, &prev_ref.reference.sha)? {
+                bail!(ErrorKind::NonFastForward(name.to_string()));
+            }
+        }
+
+        let parent = prev_ref.map(|r| &r.reference);
+        let recipients = recipients(settings)?;
+        push_to_s3(s3, settings, &local_ref, parent, &recipients, push_options)?;
+
+        // Heads that appeared since the remote was listed were pushed by
+        // another client at the same time, which s3 can't prevent. They are
+        // kept, like stale heads, but the push is reported as failed so the
+        // user fetches and merges them.
+        let listed = remote_refs
+            .iter()
+            .flat_map(|r| r.latest_first.iter())
+            .map(|r| r.reference.sha.as_str())
+            .collect_vec();
+        let contended = self.list_refs()?.get(name).is_some_and(|rs| {
+            rs.latest_first.iter().any(|r| {
+                r.reference.sha != local_ref.sha && !listed.contains(&r.reference.sha.as_str())
+            })
+        });
+
+        // Delete any ref that is an ancestor of the one we pushed, or every
+        // other head if stale heads are not being kept
+        let mut stale = vec![];
+        for r in remote_refs.iter().flat_map(|r| r.latest_first.iter()) {
+            if r.reference.sha == local_ref.sha {
+                continue;
+            }
+            if !push_options.keep_stale || git::is_ancestor(&local_ref.sha, &r.reference.sha)? {
+                delete_head(s3, settings, r)?;
+            } else {
+                stale.push(r);
+            }
+        }
+        if !stale.is_empty() {
+            let pushed = RemoteRef {
+                object: settings.bundle_key(&local_ref),
+                updated: String::new(),
+                size: 0,
+                pushed_at: None,
+                reference: local_ref,
+            };
+            self.warn_stale_heads(&pushed, &stale);
+        }
+
+        if contended {
+            bail!(ErrorKind::LockContention(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // Let the user know a ref has diverged, and how to bring it back together
+    pub fn warn_stale_heads(&self, latest: &RemoteRef, stale: &[&RemoteRef]) {
+        warn_stale_heads(&*self.s3, &self.settings, latest, stale)
+    }
+}
+
+#[derive(Debug)]
+pub struct GitRef {
+    pub name: String,
+    pub sha: String,
+}
+
+fn is_sha(s: &str) -> bool {
+    sha_object_format(s).is_some() && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+// The hash algorithm a sha of this length is from
+fn sha_object_format(sha: &str) -> Option<&'static str> {
+    match sha.len() {
+        40 => Some(config::OBJECT_FORMAT_SHA1),
+        64 => Some(config::OBJECT_FORMAT_SHA256),
+        _ => None,
+    }
+}
+
+// The hash algorithm of the remote's heads: the one its config records, or
+// for a remote without one, that of the heads it has. None if it has neither.
+fn object_format(settings: &Settings, refs: &HashMap<String, RemoteRefs>) -> Option<String> {
+    match &settings.config {
+        Some(config) => Some(config.object_format.to_owned()),
+        None => heads_object_format(refs).map(|f| f.to_string()),
+    }
+}
+
+fn heads_object_format(refs: &HashMap<String, RemoteRefs>) -> Option<&'static str> {
+    refs.values()
+        .flat_map(|rs| rs.latest_first.iter())
+        .find_map(|r| sha_object_format(&r.reference.sha))
+}
+
+impl GitRef {
+    // The inverse of bundle_path, None if the key isn't in that format
+    fn from_bundle_path(root: &str, key: &str, extension: &str) -> Option<GitRef> {
+        let path = key.strip_prefix(root)?.strip_prefix('/')?;
+        let path = path.strip_suffix(extension)?.strip_suffix('.')?;
+        let last_slash = path.rfind('/')?;
+        let (name, sha) = (&path[..last_slash], &path[(last_slash + 1)..]);
+        if name.is_empty() || !is_sha(sha) {
+            return None;
+        }
+        Some(GitRef {
+            name: name.to_string(),
+            sha: sha.to_string(),
+        })
+    }
+
+    // The name a head is advertised as when it is not the latest for its ref
+    pub fn stale_name(&self) -> String {
+        format!("{}__{}", self.name, self.sha.get(0..7).unwrap_or(&self.sha))
+    }
+
+    fn bundle_path(&self, root: String, extension: &str) -> String {
+        let mut s = String::new();
+        s.push_str(&format!(
+            "{}/{}/{}.{}",
+            &root, &self.name, &self.sha, extension
+        ));
+        s
+    }
+}
+
+#[derive(Debug)]
+pub struct RemoteRef {
+    pub object: storage::Key,
+    pub updated: String,
+    pub size: i64,
+    // When the pushing client recorded the push, only fetched for refs with
+    // multiple heads
+    pub pushed_at: Option<String>,
+    pub reference: GitRef,
+}
+
+#[derive(Debug)]
+pub struct RemoteRefs {
+    pub latest_first: Vec<RemoteRef>,
+}
+
+impl RemoteRefs {
+    pub fn latest_ref(&self) -> &RemoteRef {
+        self.latest_first.first().unwrap()
+    }
+}
+
+// Download, decrypt and decompress a bundle from s3
+fn download_bundle(s3: &dyn Storage, o: &storage::Key, bundle_file: &Path) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(bundle_file)
+        .chain_err(|| format!("couldn't create {:?}", bundle_file))?;
+    let (stages, download) = download_stages(s3, o)?;
+    let mut pipeline = Pipeline::spawn(stages, Some(download), true)?;
+    let copied = io::copy(&mut pipeline, &mut file);
+    pipeline.finish()?;
+    copied.chain_err(|| format!("couldn't write {:?}", bundle_file))?;
+    Ok(())
+}
+
+// The commands that turn an object back into a bundle, which read the
+// download (returned to be used as their input)
+fn download_stages(
+    s3: &dyn Storage,
+    o: &storage::Key,
+) -> Result<(Vec<Stage>, Box<dyn Read + Send>)> {
+    let download = s3.get_reader(o)?;
+    let compression = Compression::from_metadata(&download.metadata)
+        .chain_err(|| format!("can't read {}", s3.url(o)))?;
+    let mut stages = vec![Stage::new(
+        gpg::decrypt_command(),
+        ErrorKind::Crypto("decrypt".to_string()),
+    )];
+    stages.extend(compression.decompress_stage());
+    Ok((stages, download.body))
+}
+
+// The bundle is decrypted and unbundled as it's downloaded
+fn fetch_from_s3(s3: &dyn Storage, settings: &Settings, r: &GitRef) -> Result<()> {
+    if settings.layout() == config::LAYOUT_PACKS {
+        return packs::fetch(s3, settings, r);
+    }
+    unbundle_object(s3, &settings.bundle_key(r), Some(&r.name))
+}
+
+// Download a bundle into the current repository
+fn unbundle_object(s3: &dyn Storage, o: &storage::Key, ref_name: Option<&str>) -> Result<()> {
+    let (mut stages, download) = download_stages(s3, o)?;
+    stages.push(Stage::new(
+        git::bundle_unbundle_command(ref_name),
+        "git unbundle failed",
+    ));
+    Pipeline::spawn(stages, Some(download), false)?.finish()
+}
+
+// Download a pack into the current repository
+fn index_object(s3: &dyn Storage, o: &storage::Key) -> Result<()> {
+    let (mut stages, download) = download_stages(s3, o)?;
+    stages.push(Stage::new(
+        git::index_pack_command(),
+        "git index-pack failed",
+    ));
+    Pipeline::spawn(stages, Some(download), false)?.finish()
+}
+
+// Along with its snapshot, if the remote keeps them
+fn delete_head(s3: &dyn Storage, settings: &Settings, r: &RemoteRef) -> Result<()> {
+    s3.del(&r.object)?;
+    if settings.snapshots() {
+        s3.del(&settings.snapshot_key(&r.reference))?;
+    }
+    Ok(())
+}
+
+// The bundle is compressed, encrypted and uploaded as git writes it
+fn push_to_s3(
+    s3: &dyn Storage,
+    settings: &Settings,
+    r: &GitRef,
+    parent: Option<&GitRef>,
+    recipients: &[String],
+    push_options: &PushOptions,
+) -> Result<()> {
+    let mut metadata = push_metadata(r, parent);
+    if let Some(message) = &push_options.message {
+        metadata.insert("message".to_string(), encode_metadata(message));
+    }
+    let encoding = Encoding {
+        recipients,
+        compression: compression(settings)?,
+        storage_class: push_options.storage_class.as_deref(),
+    };
+    // Uploaded first, so that every head a client lists has its snapshot
+    if settings.snapshots() {
+        push_snapshot(s3, settings, r, &metadata, &encoding)?;
+    }
+    if settings.layout() == config::LAYOUT_PACKS {
+        return packs::push(s3, settings, r, &metadata, &encoding);
+    }
+    let bundle = git::bundle_create_command(&r.name, &[]);
+    upload_bundle(s3, &settings.bundle_key(r), bundle, metadata, &encoding)
+}
+
+fn push_snapshot(
+    s3: &dyn Storage,
+    settings: &Settings,
+    r: &GitRef,
+    metadata: &HashMap<String, String>,
+    encoding: &Encoding,
+) -> Result<()> {
+    let tmp_dir = Builder::new()
+        .prefix("s3_snapshot")
+        .tempdir()
+        .chain_err(|| "mktemp dir failed")?;
+    let shallow_file = tmp_dir.path().join("shallow");
+    fs::write(&shallow_file, format!("{}\n", r.sha)).chain_err(|| "couldn't write shallow file")?;
+    let pack = Stage::new(
+        git::snapshot_pack_command(&shallow_file),
+        "git pack-objects failed",
+    );
+    let input = io::Cursor::new(format!("{}\n", r.sha));
+    upload_output(
+        s3,
+        &settings.snapshot_key(r),
+        pack,
+        Some(Box::new(input)),
+        metadata.clone(),
+        encoding,
+    )
+}
+
+// How uploaded bundles are compressed, encrypted and stored
+struct Encoding<'a> {
+    recipients: &'a [String],
+    compression: Compression,
+    storage_class: Option<&'a str>,
+}
+
+// Upload the bundle written by a git command, recording how it was encoded
+fn upload_bundle(
+    s3: &dyn Storage,
+    o: &storage::Key,
+    bundle: Command,
+    metadata: HashMap<String, String>,
+    encoding: &Encoding,
+) -> Result<()> {
+    let bundle = Stage::new(bundle, "git bundle failed");
+    upload_output(s3, o, bundle, None, metadata, encoding)
+}
+
+// The output of `source`, which reads `input`, is uploaded the same way
+fn upload_output(
+    s3: &dyn Storage,
+    o: &storage::Key,
+    source: Stage,
+    input: Option<Box<dyn Read + Send>>,
+    mut metadata: HashMap<String, String>,
+    encoding: &Encoding,
+) -> Result<()> {
+    let compression = encoding.compression;
+    metadata.insert(
+        "recipients".to_string(),
+        recipients_metadata(encoding.recipients),
+    );
+    metadata.insert(
+        compress::METADATA_KEY.to_string(),
+        compression.name().to_string(),
+    );
+
+    let mut stages = vec![source];
+    stages.extend(compression.compress_stage());
+    stages.push(Stage::new(
+        gpg::encrypt_command(encoding.recipients, compression.gpg_compresses()),
+        ErrorKind::Crypto("encrypt".to_string()),
+    ));
+    let mut pipeline = Pipeline::spawn(stages, input, true)?;
+    let uploaded = s3.put_reader(&mut pipeline, o, &metadata, encoding.storage_class);
+    // A failed command is why the upload failed, if it did
+    pipeline.finish()?;
+    uploaded
+}
+
+// Bundles are compressed by gpg unless the clone chooses otherwise
+fn compression(settings: &Settings) -> Result<Compression> {
+    let key = format!("remote.{}.compression", settings.remote_alias);
+    match git::config(&key) {
+        Ok(name) => Compression::parse(&name).chain_err(|| format!("invalid {}", key)),
+        Err(_) => Ok(Compression::GpgDefault),
+    }
+}
+
+// Read from the config of the repository git commands currently use, so
+// commands that switch to another repository resolve them first
+fn recipients(settings: &Settings) -> Result<Vec<String>> {
+    match configured_recipients(settings)? {
+        Some(recipients) => Ok(recipients),
+        None => git::config("user.email").map(|recip| vec![recip]),
+    }
+}
+
+// Recipients set for this clone or in the remote's config, which must agree
+// so that nobody pushes bundles the rest of the team can't read
+fn configured_recipients(settings: &Settings) -> Result<Option<Vec<String>>> {
+    let local = local_recipients(settings);
+    let remote = settings
+        .config
+        .as_ref()
+        .map(|c| c.recipients.clone())
+        .filter(|r| !r.is_empty());
+    match (local, remote) {
+        (Some(local), Some(remote))
+            if recipients_metadata(&local) != recipients_metadata(&remote) =>
+        {
+            bail!(
+                "remote.{}.gpgRecipients ({}) conflicts with the recipients in the remote's config ({}), unset it or update the remote with `git-remote-s3 admin init --force`",
+                settings.remote_alias,
+                local.join(" "),
+                remote.join(" ")
+            )
+        }
+        (Some(local), _) => Ok(Some(local)),
+        (None, remote) => Ok(remote),
+    }
+}
+
+fn local_recipients(settings: &Settings) -> Option<Vec<String>> {
+    git::config(&format!("remote.{}.gpgRecipients", settings.remote_alias))
+        .ok()
+        .map(|config| {
+            config
+                .split_ascii_whitespace()
+                .map(|s| s.to_string())
+                .collect_vec()
+        })
+}
+
+// Bundles record who they were encrypted for, so re-encrypting can tell which
+// are already done
+fn recipients_metadata(recipients: &[String]) -> String {
+    recipients.iter().sorted().dedup().join(" ")
+}
+
+// Describes who pushed a bundle, stored as `x-amz-meta-*` headers so that
+// multiple heads of a branch can be told apart
+fn push_metadata(r: &GitRef, parent: Option<&GitRef>) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    let mut add = |key: &str, value: String| {
+        if !value.is_empty() {
+            metadata.insert(key.to_string(), encode_metadata(&value));
+        }
+    };
+    add("pusher-name", git::config("user.name").unwrap_or_default());
+    add(
+        "pusher-email",
+        git::config("user.email").unwrap_or_default(),
+    );
+    add("hostname", hostname());
+    add("tool-version", env!("CARGO_PKG_VERSION").to_string());
+    add("source-ref", r.name.to_owned());
+    add(
+        "parent-sha",
+        parent.map(|p| p.sha.to_owned()).unwrap_or_default(),
+    );
+    add(
+        "pushed-at",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    );
+    metadata
+}
+
+// s3 only accepts US-ASCII user metadata, so other characters (and `%`
+// itself) are stored percent-encoded as UTF-8
+fn encode_metadata(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// The inverse of encode_metadata, for showing a value
+fn decode_metadata(value: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(decoded) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn hostname() -> String {
+    Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|h| h.trim().to_string())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .unwrap_or_default()
+}
+
+// Let the user know a branch has diverged on s3, and how to bring it back together
+fn warn_stale_heads(
+    s3: &dyn Storage,
+    settings: &Settings,
+    latest: &RemoteRef,
+    stale: &[&RemoteRef],
+) {
+    if stale.is_empty() {
+        return;
+    }
+    let describe = |r: &RemoteRef| {
+        let metadata = s3.head(&r.object).map(|h| h.metadata).unwrap_or_default();
+        let value = |key| metadata.get(key).map(|v| decode_metadata(v));
+        let pusher = match (value("pusher-name"), value("pusher-email")) {
+            (Some(name), Some(email)) => format!("{} <{}>", name, email),
+            (Some(who), None) | (None, Some(who)) => who,
+            (None, None) => "unknown".to_string(),
+        };
+        let when = metadata.get("pushed-at").unwrap_or(&r.updated);
+        format!("{} (pushed by {} at {})", r.reference.sha, pusher, when)
+    };
+
+    let name = &latest.reference.name;
+    settings.warn(&format!(
+        "{} has {} heads on s3, using {}",
+        name,
+        stale.len() + 1,
+        describe(latest)
+    ));
+    for r in stale {
+        settings.warn(&format!("  stale head {}", describe(r)));
+    }
+    let branch = name.trim_start_matches("refs/heads/");
+    settings.warn("merge the stale heads to consolidate them, e.g.");
+    for r in stale {
+        settings.warn(&format!(
+            "  git pull {} {}",
+            settings.remote_alias,
+            r.reference.stale_name().trim_start_matches("refs/heads/")
+        ));
+    }
+    settings.warn(&format!("  git push {} {}", settings.remote_alias, branch));
+}
+
+fn lease_matches(expected: &str, current: Option<&str>) -> bool {
+    match current {
+        Some(sha) => sha == expected,
+        // An all-zero (or empty) expectation means the ref must not exist yet
+        None => expected.chars().all(|c| c == '0'),
+    }
+}
+
+// Per-push behaviour requested with `git push -o <key>=<value>`
+#[derive(Debug, PartialEq)]
+pub struct PushOptions {
+    // Keep heads that are not ancestors of the pushed one as `<ref>__<sha>`
+    pub keep_stale: bool,
+    pub storage_class: Option<String>,
+    pub message: Option<String>,
+    // The remote sha expected by --force-with-lease, which is set by git
+    // rather than with -o
+    pub lease: Option<String>,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            keep_stale: true,
+            storage_class: None,
+            message: None,
+            lease: None,
+        }
+    }
+}
+
+impl PushOptions {
+    pub fn parse(raw: &[String]) -> Result<PushOptions> {
+        let mut push_options = PushOptions::default();
+        for option in raw {
+            let (key, value) = match option.find('=') {
+                Some(idx) => (&option[..idx], &option[(idx + 1)..]),
+                None => (option.as_str(), ""),
+            };
+            match key {
+                "keep-stale" => {
+                    push_options.keep_stale = match value {
+                        "true" | "" => true,
+                        "false" => false,
+                        _ => bail!("invalid value for push option keep-stale: {}", value),
+                    }
+                }
+                "storage-class" if !value.is_empty() => {
+                    push_options.storage_class = Some(value.to_string())
+                }
+                "message" => push_options.message = Some(value.to_string()),
+                _ => bail!("unsupported push option: {}", option),
+            }
+        }
+        Ok(push_options)
+    }
+}
+
+fn list_remote_refs(s3: &dyn Storage, settings: &Settings) -> Result<HashMap<String, RemoteRefs>> {
+    let bundle_root = settings.bundle_root();
+    let extension = settings.head_extension();
+    let objects = s3.list(&settings.dir_prefix(&bundle_root))?;
+    let mut ignored = vec![];
+    let map: HashMap<String, Vec<RemoteRef>> = objects
+        .into_iter()
+        .filter_map(|o| {
+            let k = o.key;
+            if settings.is_reserved(&k) {
+                return None;
+            }
+            let reference = match GitRef::from_bundle_path(&bundle_root, &k, extension) {
+                Some(reference) => reference,
+                None => {
+                    ignored.push(k);
+                    return None;
+                }
+            };
+            Some((
+                reference.name.to_owned(),
+                RemoteRef {
+                    object: storage::Key {
+                        bucket: settings.root.bucket.to_owned(),
+                        key: k,
+                    },
+                    updated: o.last_modified,
+                    size: o.size,
+                    pushed_at: None,
+                    reference,
+                },
+            ))
+        })
+        .into_group_map();
+    // The remote is listed for most commands, so only warn the first time
+    if !ignored.is_empty() {
+        settings.warned_ignored.call_once(|| {
+            for key in ignored {
+                let o = storage::Key {
+                    bucket: settings.root.bucket.to_owned(),
+                    key,
+                };
+                settings.warn(&format!(
+                    "ignoring {}, which is not a <ref>/<sha>.{} key",
+                    s3.url(&o),
+                    extension
+                ));
+            }
+        });
+    }
+    let mut refs = HashMap::new();
+    for (name, mut heads) in map {
+        if heads.len() > 1 {
+            for head in heads.iter_mut() {
+                head.pushed_at = s3.head(&head.object)?.metadata.remove("pushed-at");
+            }
+        }
+        let sorted =
+            sorted_remote_refs(heads, |base, r| git::is_ancestor(base, r).unwrap_or(false));
+        refs.insert(name, sorted);
+    }
+    Ok(refs)
+}
+
+// Order the heads of a ref, latest first. The latest is a head that has all
+// the others as ancestors if there is one, otherwise the most recently pushed
+// according to the pushing client, and then to s3's (less precise) clock.
+// Heads pushed by older clients don't record the time, so the pushing clients'
+// times are only compared when every head has one.
+fn sorted_remote_refs<F>(refs: Vec<RemoteRef>, is_ancestor: F) -> RemoteRefs
+where
+    F: Fn(&str, &str) -> bool,
+{
+    let all_pushed_at = refs.iter().all(|r| r.pushed_at.is_some());
+    let mut refs = refs
+        .into_iter()
+        .sorted_by(|a, b| {
+            if all_pushed_at {
+                (&b.pushed_at, &b.updated).cmp(&(&a.pushed_at, &a.updated))
+            } else {
+                b.updated.cmp(&a.updated)
+            }
+        })
+        .collect_vec();
+    if refs.len() > 1 {
+        let descendant = refs.iter().position(|r| {
+            refs.iter().all(|other| {
+                other.reference.sha == r.reference.sha
+                    || is_ancestor(&r.reference.sha, &other.reference.sha)
+            })
+        });
+        if let Some(idx) = descendant {
+            let latest = refs.remove(idx);
+            refs.insert(0, latest);
+        }
+    }
+    RemoteRefs { latest_first: refs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(sha: &str, updated: &str, pushed_at: Option<&str>) -> RemoteRef {
+        RemoteRef {
+            object: storage::Key {
+                bucket: "bucket".to_string(),
+                key: format!("prefix/refs/heads/master/{}.bundle", sha),
+            },
+            updated: updated.to_string(),
+            size: 0,
+            pushed_at: pushed_at.map(|p| p.to_string()),
+            reference: GitRef {
+                name: "refs/heads/master".to_string(),
+                sha: sha.to_string(),
+            },
+        }
+    }
+
+    fn order(refs: &RemoteRefs) -> Vec<&str> {
+        refs.latest_first
+            .iter()
+            .map(|r| r.reference.sha.as_str())
+            .collect()
+    }
+
+    fn unrelated(_: &str, _: &str) -> bool {
+        false
+    }
+
+    fn parse(key: &str) -> Option<(String, String)> {
+        GitRef::from_bundle_path("prefix", key, "bundle").map(|r| (r.name, r.sha))
+    }
+
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn typed_errors_are_found_through_context() {
+        let denied: Result<()> = Err(ErrorKind::AccessDenied("s3://bucket/key".to_string()).into());
+        let e = denied
+            .chain_err(|| "couldn't get item")
+            .chain_err(|| "couldn't fetch refs/heads/master")
+            .unwrap_err();
+        assert!(matches!(e.typed_kind(), Some(ErrorKind::AccessDenied(_))));
+
+        let e: Error = "couldn't get item".into();
+        assert!(e.typed_kind().is_none());
+    }
+
+    #[test]
+    fn s3_urls_need_a_prefix() {
+        let key = parse_url("s3://bucket/prefix/").unwrap();
+        assert_eq!((&*key.bucket, &*key.key), ("bucket", "prefix"));
+        assert!(parse_url("s3://bucket/").is_err());
+        assert!(parse_url("s3://bucket").is_err());
+    }
+
+    #[test]
+    fn bundle_path_round_trips() {
+        let r = GitRef {
+            name: "refs/heads/feature/x".to_string(),
+            sha: SHA.to_string(),
+        };
+        let key = r.bundle_path("prefix".to_string(), "bundle");
+        assert_eq!(
+            parse(&key),
+            Some(("refs/heads/feature/x".to_string(), SHA.to_string()))
+        );
+    }
+
+    #[test]
+    fn sha256_bundle_keys_are_bundles() {
+        let sha = "8fd2872d5c5493f15612e5095e083cf910672360be3815fed8dd2b970f297452";
+        assert_eq!(
+            parse(&format!("prefix/refs/heads/master/{}.bundle", sha)),
+            Some(("refs/heads/master".to_string(), sha.to_string()))
+        );
+        assert_eq!(sha_object_format(sha), Some(config::OBJECT_FORMAT_SHA256));
+        assert_eq!(sha_object_format(SHA), Some(config::OBJECT_FORMAT_SHA1));
+        // neither length
+        assert_eq!(
+            parse(&format!("prefix/refs/heads/master/{}.bundle", &sha[..50])),
+            None
+        );
+    }
+
+    #[test]
+    fn foreign_keys_are_not_bundles() {
+        assert_eq!(parse("prefix/README"), None);
+        assert_eq!(parse("prefix/x"), None);
+        assert_eq!(parse("prefix"), None);
+        assert_eq!(parse(&format!("prefix/{}.bundle", SHA)), None);
+        assert_eq!(
+            parse(&format!("prefix2/refs/heads/master/{}.bundle", SHA)),
+            None
+        );
+        assert_eq!(
+            parse(&format!("other/refs/heads/master/{}.bundle", SHA)),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_bundle_keys_are_not_bundles() {
+        // wrong suffix
+        assert_eq!(parse(&format!("prefix/refs/heads/master/{}", SHA)), None);
+        assert_eq!(
+            parse(&format!("prefix/refs/heads/master/{}.tmp", SHA)),
+            None
+        );
+        // not a sha
+        assert_eq!(parse("prefix/refs/heads/master/abc.bundle"), None);
+        assert_eq!(parse("prefix/refs/heads/master/.bundle"), None);
+        assert_eq!(
+            parse(&format!(
+                "prefix/refs/heads/master/{}.bundle",
+                SHA.to_uppercase()
+            )),
+            None
+        );
+        assert_eq!(
+            parse("prefix/refs/heads/master/0123456789abcdef0123456789abcdef0123456g.bundle"),
+            None
+        );
+        // no ref name
+        assert_eq!(parse(&format!("prefix//{}.bundle", SHA)), None);
+    }
+
+    #[test]
+    fn descendant_of_all_heads_is_latest() {
+        let heads = vec![
+            head(
+                "aaa",
+                "2020-01-01T00:00:02.000Z",
+                Some("2020-01-01T00:00:02.000Z"),
+            ),
+            head(
+                "bbb",
+                "2020-01-01T00:00:01.000Z",
+                Some("2020-01-01T00:00:01.000Z"),
+            ),
+            head(
+                "ccc",
+                "2020-01-01T00:00:03.000Z",
+                Some("2020-01-01T00:00:03.000Z"),
+            ),
+        ];
+        // bbb descends from both aaa and ccc, even though it was pushed first
+        let refs = sorted_remote_refs(heads, |base, r| base == "bbb" && r != "bbb");
+        assert_eq!(order(&refs), vec!["bbb", "ccc", "aaa"]);
+    }
+
+    #[test]
+    fn partial_descendant_is_not_latest() {
+        let heads = vec![
+            head("aaa", "2020-01-01T00:00:01.000Z", None),
+            head("bbb", "2020-01-01T00:00:02.000Z", None),
+            head("ccc", "2020-01-01T00:00:03.000Z", None),
+        ];
+        // aaa descends from bbb, but not ccc
+        let refs = sorted_remote_refs(heads, |base, r| base == "aaa" && r == "bbb");
+        assert_eq!(order(&refs), vec!["ccc", "bbb", "aaa"]);
+    }
+
+    #[test]
+    fn push_time_breaks_ties_in_last_modified() {
+        let heads = vec![
+            head(
+                "aaa",
+                "2020-01-01T00:00:01.000Z",
+                Some("2020-01-01T00:00:01.250Z"),
+            ),
+            head(
+                "bbb",
+                "2020-01-01T00:00:01.000Z",
+                Some("2020-01-01T00:00:01.500Z"),
+            ),
+        ];
+        let refs = sorted_remote_refs(heads, unrelated);
+        assert_eq!(order(&refs), vec!["bbb", "aaa"]);
+    }
+
+    #[test]
+    fn push_time_preferred_over_last_modified() {
+        // A slow upload can finish after a push that started later
+        let heads = vec![
+            head(
+                "aaa",
+                "2020-01-01T00:00:09.000Z",
+                Some("2020-01-01T00:00:01.000Z"),
+            ),
+            head(
+                "bbb",
+                "2020-01-01T00:00:05.000Z",
+                Some("2020-01-01T00:00:04.000Z"),
+            ),
+        ];
+        let refs = sorted_remote_refs(heads, unrelated);
+        assert_eq!(order(&refs), vec!["bbb", "aaa"]);
+    }
+
+    #[test]
+    fn last_modified_used_without_push_time() {
+        let heads = vec![
+            head("aaa", "2020-01-01T00:00:01.000Z", None),
+            head("bbb", "2020-01-01T00:00:02.000Z", None),
+        ];
+        let refs = sorted_remote_refs(heads, unrelated);
+        assert_eq!(order(&refs), vec!["bbb", "aaa"]);
+    }
+
+    #[test]
+    fn metadata_is_encoded_as_ascii() {
+        let value = "Zoë 100% 日本\n";
+        let encoded = encode_metadata(value);
+        assert!(
+            encoded.bytes().all(|b| (b' '..=b'~').contains(&b)),
+            "{}",
+            encoded
+        );
+        assert_eq!(encoded, "Zo%C3%AB 100%25 %E6%97%A5%E6%9C%AC%0A");
+        assert_eq!(decode_metadata(&encoded), value);
+        assert_eq!(decode_metadata("50% off"), "50% off");
+    }
+
+    #[test]
+    fn push_time_ignored_unless_every_head_has_it() {
+        // A head pushed by an older client doesn't lose for lacking the time
+        let heads = vec![
+            head(
+                "aaa",
+                "2020-01-01T00:00:01.000Z",
+                Some("2020-01-01T00:00:00.900Z"),
+            ),
+            head("bbb", "2020-01-01T00:00:02.000Z", None),
+        ];
+        let refs = sorted_remote_refs(heads, unrelated);
+        assert_eq!(order(&refs), vec!["bbb", "aaa"]);
+    }
+}
@@ -1,77 +1,39 @@
-#![recursion_limit = "1024"]
 #[macro_use]
 extern crate error_chain;
+extern crate git_remote_s3;
 extern crate itertools;
-extern crate rusoto_core;
-extern crate rusoto_s3;
 
-use rusoto_core::Region;
-use rusoto_s3::S3Client;
+use git_remote_s3::errors::*;
+use git_remote_s3::{admin, Config, GitRef, PushOptions, Remote, RemoteRefs};
 
 use itertools::Itertools;
-use tempfile::Builder;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io;
 use std::path::PathBuf;
 
-pub mod errors {
-    error_chain! {}
-}
-use errors::*;
-mod git;
-mod gpg;
-mod s3;
-
 quick_main!(run);
 
-struct Settings {
-    //git_dir: PathBuf,
-    remote_alias: String,
-    //remote_url: String,
-    root: s3::Key,
-}
-
 fn run() -> Result<()> {
-    let region = if let Ok(endpoint) = env::var("S3_ENDPOINT") {
-        Region::Custom {
-            name: String::from("us-east-1"),
-            endpoint,
-        }
-    } else {
-        Region::default()
-    };
+    let args = env::args().skip(1).collect_vec();
 
-    let s3 = S3Client::new(region);
+    // When run directly (rather than by git with `<alias> <url>`), the
+    // administrative commands are available
+    let is_url = |arg: Option<&String>| arg.is_some_and(|a| a.contains("://"));
+    if args.first().map(String::as_str) == Some("admin") && !is_url(args.get(1)) {
+        return admin::run(&args[1..]);
+    }
+
+    if args.is_empty() {
+        bail!("usage: git-remote-s3 <alias> <url> (run by git), or git-remote-s3 admin <command>");
+    }
 
-    let mut args = env::args();
-    args.next();
+    let mut args = args.into_iter();
     let alias = args.next().chain_err(|| "must provide alias")?;
     let url = args.next().chain_err(|| "must provide url")?;
 
-    let (bucket, path) = {
-        if !url.starts_with("s3://") {
-            bail!("remote url does not start with s3://. expected a url in the format s3://bucket/prefix")
-        }
-        let url = &url[5..];
-        let slash = match url.find('/') {
-            Some(idx) => idx,
-            None => {
-                bail!("remote url does not appear to have a prefix. expected a url in the format s3://bucket/prefix");
-            }
-        };
-        let bucket = url.get(..slash).unwrap();
-        let end = if url.ends_with('/') {
-            url.len() - 1
-        } else {
-            url.len()
-        };
-        let path = url.get((slash + 1)..end).unwrap();
-        (bucket, path)
-    };
-
     let git_dir = PathBuf::from(env::var("GIT_DIR").chain_err(|| "GIT_DIR not set")?);
     let cur_dir = env::current_dir().chain_err(|| "could not get pwd")?;
     let work_dir = cur_dir.join(&git_dir).join("remote-s3").join(&alias);
@@ -79,119 +41,83 @@ fn run() -> Result<()> {
     fs::create_dir_all(&work_dir)
         .chain_err(|| format!("could not create work dir: {:?}", work_dir))?;
 
-    let settings = Settings {
-        //git_dir,
-        //remote_url: url.to_owned(),
-        remote_alias: alias,
-        root: s3::Key {
-            bucket: bucket.to_string(),
-            key: path.to_string(),
+    // Fails before git starts talking to us if this clone disagrees with the remote
+    let remote = Remote::open(
+        &url,
+        Config {
+            alias,
+            warn: Some(warn),
+            ..Default::default()
         },
-    };
+    )?;
 
-    cmd_loop(&s3, &settings)
+    cmd_loop(&remote)
 }
 
-#[derive(Debug)]
-struct GitRef {
-    name: String,
-    sha: String,
+fn warn(message: &str) {
+    eprintln!("warning: {}", message);
 }
 
-impl GitRef {
-    fn bundle_path(&self, root: String) -> String {
-        let mut s = String::new();
-        s.push_str(&format!("{}/{}/{}.bundle", &root, &self.name, &self.sha));
-        s
+// The remote's heads as listed for this batch, by `list` or the first fetch
+type Listed = Option<HashMap<String, RemoteRefs>>;
+
+fn cmd_fetch(
+    remote: &Remote,
+    options: &Options,
+    listed: &mut Listed,
+    warned: &mut HashSet<String>,
+    sha: &str,
+    name: &str,
+) -> Result<()> {
+    if name == "HEAD" {
+        // Ignore head, as it's guaranteed to point to a ref we already downloaded
+        return Ok(());
     }
-}
 
-#[derive(Debug)]
-struct RemoteRef {
-    object: s3::Key,
-    updated: String,
-    reference: GitRef,
-}
-
-#[derive(Debug)]
-struct RemoteRefs {
-    by_update_time: Vec<RemoteRef>,
-}
-
-impl RemoteRefs {
-    fn latest_ref(&self) -> &RemoteRef {
-        self.by_update_time.get(0).unwrap()
+    // Listing every head is the slow part, so it's done once per batch
+    if listed.is_none() {
+        *listed = Some(remote.list_refs()?);
     }
-}
-
-fn fetch_from_s3(s3: &S3Client, settings: &Settings, r: &GitRef) -> Result<()> {
-    let tmp_dir = Builder::new()
-        .prefix("s3_fetch")
-        .tempdir()
-        .chain_err(|| "mktemp dir failed")?;
-    let bundle_file = tmp_dir.path().join("bundle");
-    let enc_file = tmp_dir.path().join("buncle_enc");
-
-    let path = r.bundle_path(settings.root.key.to_owned());
-    let o = s3::Key {
-        bucket: settings.root.bucket.to_owned(),
-        key: path,
-    };
-    s3::get(s3, &o, &enc_file)?;
-
-    gpg::decrypt(&enc_file, &bundle_file)?;
-
-    git::bundle_unbundle(&bundle_file, &r.name)?;
-
-    Ok(())
-}
-
-fn push_to_s3(s3: &S3Client, settings: &Settings, r: &GitRef) -> Result<()> {
-    let tmp_dir = Builder::new()
-        .prefix("s3_push")
-        .tempdir()
-        .chain_err(|| "mktemp dir failed")?;
-    let bundle_file = tmp_dir.path().join("bundle");
-    let enc_file = tmp_dir.path().join("buncle_enc");
-
-    git::bundle_create(&bundle_file, &r.name)?;
-
-    let recipients = git::config(&format!("remote.{}.gpgRecipients", settings.remote_alias))
-        .map(|config| {
-            config
-                .split_ascii_whitespace()
-                .map(|s| s.to_string())
-                .collect_vec()
-        })
-        .or_else(|_| git::config("user.email").map(|recip| vec![recip]))?;
-
-    gpg::encrypt(&recipients, &bundle_file, &enc_file)?;
-
-    let path = r.bundle_path(settings.root.key.to_owned());
-    let o = s3::Key {
-        bucket: settings.root.bucket.to_owned(),
-        key: path,
+    let all_remote_refs = listed.as_ref().unwrap();
+    // git doesn't check this itself when fetching into an existing repository
+    remote.check_object_format(all_remote_refs)?;
+    // Stale heads are advertised as `<ref>__<short_sha>`, so look up the head
+    // that was actually listed to find the bundle
+    let remote_ref = all_remote_refs
+        .values()
+        .flat_map(|rs| rs.latest_first.iter())
+        .find(|r| {
+            r.reference.sha == sha && (r.reference.name == name || r.reference.stale_name() == name)
+        });
+    let git_ref = match remote_ref {
+        Some(r) => GitRef {
+            name: r.reference.name.to_owned(),
+            sha: sha.to_string(),
+        },
+        None => GitRef {
+            name: name.to_string(),
+            sha: sha.to_string(),
+        },
     };
-    s3::put(s3, &enc_file, &o)?;
-
-    Ok(())
-}
+    match options.depth {
+        Some(depth) => remote.fetch_shallow(&git_ref, depth)?,
+        None => remote.fetch(&git_ref)?,
+    }
 
-fn cmd_fetch(s3: &S3Client, settings: &Settings, sha: &str, name: &str) -> Result<()> {
-    if name == "HEAD" {
-        // Ignore head, as it's guaranteed to point to a ref we already downloaded
-        return Ok(());
+    // A fetch of several heads of the same ref only needs to warn once
+    let remote_refs = all_remote_refs
+        .get(&git_ref.name)
+        .filter(|_| warned.insert(git_ref.name.to_owned()));
+    if let Some(remote_refs) = remote_refs {
+        let stale = remote_refs.latest_first.iter().skip(1).collect_vec();
+        remote.warn_stale_heads(remote_refs.latest_ref(), &stale);
     }
-    let git_ref = GitRef {
-        name: name.to_string(),
-        sha: sha.to_string(),
-    };
-    fetch_from_s3(s3, settings, &git_ref)?;
-    println!();
     Ok(())
 }
 
-fn cmd_push(s3: &S3Client, settings: &Settings, push_ref: &str) -> Result<()> {
+// Every ref is answered with `ok` or `error`, so one that can't be pushed
+// doesn't stop the others
+fn cmd_push(remote: &Remote, options: &Options, push_ref: &str) -> Result<()> {
     let force = push_ref.starts_with('+');
 
     let mut split = push_ref.split(':');
@@ -201,51 +127,122 @@ fn cmd_push(s3: &S3Client, settings: &Settings, push_ref: &str) -> Result<()> {
     let dst_ref = split.next().unwrap();
 
     if src_ref != dst_ref {
-        bail!("src_ref != dst_ref")
+        println!(
+            "error {} pushing to a different ref is not supported",
+            dst_ref
+        );
+        return Ok(());
     }
 
-    let all_remote_refs = list_remote_refs(s3, settings)?;
-    let remote_refs = all_remote_refs.get(src_ref);
-    let prev_ref = remote_refs.map(|rs| rs.latest_ref());
-    let local_sha = git::rev_parse(src_ref)?;
-    let local_ref = GitRef {
-        name: src_ref.to_string(),
-        sha: local_sha,
-    };
-
-    let can_push = force
-        || match prev_ref {
-            Some(prev_ref) => {
-                if !git::is_ancestor(&local_ref.sha, &prev_ref.reference.sha)? {
-                    println!("error {} remote changed: force push to add new ref, the old ref will be kept until its merged)", dst_ref);
-                    false
-                } else {
-                    true
-                }
+    let pushed = PushOptions::parse(&options.push_options).and_then(|mut push_options| {
+        push_options.lease = options.cas.get(dst_ref).cloned();
+        remote.push(src_ref, force, &push_options)
+    });
+    match pushed {
+        Ok(()) => println!("ok {}", dst_ref),
+        Err(e) => {
+            if let Some(ErrorKind::NonFastForward(_)) = e.typed_kind() {
+                eprintln!("hint: a force push adds a new head, and the old head is kept until it's merged");
             }
-            None => true,
-        };
+            println!("error {} {}", dst_ref, push_error_reason(&e));
+        }
+    }
+    Ok(())
+}
+
+// git recognises some reasons, and explains them to the user itself
+fn push_error_reason(e: &Error) -> String {
+    match e.typed_kind() {
+        Some(ErrorKind::NonFastForward(_)) => "non-fast-forward".to_string(),
+        Some(ErrorKind::StaleLease(_)) => "stale info".to_string(),
+        Some(kind) => kind.to_string(),
+        None => e.iter().map(|e| e.to_string()).join(": "),
+    }
+}
 
-    if can_push {
-        push_to_s3(s3, settings, &local_ref)?;
+// Options set by git via the `option` command, kept for the life of the helper
+#[derive(Default)]
+struct Options {
+    // Expected remote sha per ref for --force-with-lease
+    cas: HashMap<String, String>,
+    // Raw `git push -o` strings, parsed into PushOptions when pushing
+    push_options: Vec<String>,
+    // Commits of history wanted by a shallow clone or fetch (--depth)
+    depth: Option<u32>,
+    // Whether git wants `list` to say which hash algorithm the remote uses
+    object_format: bool,
+}
 
-        // Delete any ref that is an ancestor of the one we pushed
-        for r in remote_refs.iter().flat_map(|r| r.by_update_time.iter()) {
-            if git::is_ancestor(&local_ref.sha, &r.reference.sha)? {
-                s3::del(s3, &r.object)?;
+// Option values are C-style quoted by git when they contain special characters
+fn unquote(value: &str) -> String {
+    if !(value.len() >= 2 && value.starts_with('"') && value.ends_with('"')) {
+        return value.to_string();
+    }
+    let mut bytes = Vec::new();
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some(d @ '0'..='7') => {
+                let octal: String = std::iter::once(d).chain(chars.by_ref().take(2)).collect();
+                bytes.push(u8::from_str_radix(&octal, 8).unwrap_or(b'?'));
             }
+            Some(c) => bytes.push(c as u8),
+            None => {}
         }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
 
-        println!("ok {}", dst_ref);
-    };
-
-    println!();
+fn cmd_option(options: &mut Options, name: &str, value: &str) -> Result<()> {
+    let value = unquote(value);
+    match name {
+        "push-option" => {
+            options.push_options.push(value);
+            println!("ok");
+        }
+        "cas" => match value.rfind(':') {
+            Some(idx) => {
+                options
+                    .cas
+                    .insert(value[..idx].to_string(), value[(idx + 1)..].to_string());
+                println!("ok");
+            }
+            None => println!("error invalid cas value: {}", value),
+        },
+        // Given no value by git itself, which the docs say is `true`, or the
+        // algorithm git is using, which pushes check
+        "object-format" if ["", "true", "sha1", "sha256"].contains(&value.as_str()) => {
+            options.object_format = true;
+            println!("ok");
+        }
+        "depth" => match value.parse() {
+            Ok(depth) => {
+                options.depth = Some(depth);
+                println!("ok");
+            }
+            Err(_) => println!("error invalid depth: {}", value),
+        },
+        _ => println!("unsupported"),
+    }
     Ok(())
 }
 
 // Implement protocol defined here:
 // https://github.com/git/git/blob/master/Documentation/gitremote-helpers.txt
-fn cmd_loop(s3: &S3Client, settings: &Settings) -> Result<()> {
+fn cmd_loop(remote: &Remote) -> Result<()> {
+    let mut options = Options::default();
+    let mut warned = HashSet::new();
+    // Fetches and pushes come in batches ended by a blank line, which is
+    // answered once the whole batch is done
+    let mut in_batch = false;
+    let mut listed: Listed = None;
     loop {
         let mut input = String::new();
         io::stdin()
@@ -262,11 +259,33 @@ fn cmd_loop(s3: &S3Client, settings: &Settings) -> Result<()> {
         let arg2 = iter.next();
 
         match (cmd, arg1, arg2) {
-            (Some("push"), Some(ref_arg), None) => cmd_push(s3, settings, ref_arg),
-            (Some("fetch"), Some(sha), Some(name)) => cmd_fetch(s3, settings, sha, name),
+            (Some("option"), Some(name), _) => {
+                // The value is the rest of the line and may contain spaces, or
+                // be left out (e.g. `option object-format`)
+                let value = input.trim_end().splitn(3, ' ').nth(2).unwrap_or("");
+                cmd_option(&mut options, name, value)
+            }
+            (Some("push"), Some(ref_arg), None) => {
+                in_batch = true;
+                cmd_push(remote, &options, ref_arg)
+            }
+            (Some("fetch"), Some(sha), Some(name)) => {
+                in_batch = true;
+                // A fetch can't be refused per ref, so the helper stops
+                cmd_fetch(remote, &options, &mut listed, &mut warned, sha, name)
+                    .chain_err(|| format!("couldn't fetch {}", name))
+            }
             (Some("capabilities"), None, None) => cmd_capabilities(),
-            (Some("list"), None, None) => cmd_list(s3, settings),
-            (Some("list"), Some("for-push"), None) => cmd_list(s3, settings),
+            (Some("list"), None, None) | (Some("list"), Some("for-push"), None) => {
+                cmd_list(remote, &options).map(|refs| listed = Some(refs))
+            }
+            (None, None, None) if in_batch => {
+                in_batch = false;
+                // Pushes change the heads, and another batch may come later
+                listed = None;
+                println!();
+                Ok(())
+            }
             (None, None, None) => return Ok(()),
             _ => cmd_unknown(),
         }?
@@ -279,67 +298,25 @@ fn cmd_unknown() -> Result<()> {
     Ok(())
 }
 
-fn list_remote_refs(s3: &S3Client, settings: &Settings) -> Result<HashMap<String, RemoteRefs>> {
-    let result = s3::list(s3, &settings.root)?;
-    let objects = match result.contents {
-        Some(l) => l,
-        None => vec![],
-    };
-    let map: HashMap<String, Vec<RemoteRef>> = objects
-        .into_iter()
-        .flat_map(|o| {
-            o.key.to_owned().map(|k| {
-                let last_slash = k.rfind('/').unwrap();
-                let last_dot = k.rfind('.').unwrap();
-                let name = k
-                    .get((settings.root.key.len() + 1)..last_slash)
-                    .unwrap()
-                    .to_string();
-                let sha = k.get((last_slash + 1)..last_dot).unwrap().to_string();
-                (
-                    name.to_owned(),
-                    RemoteRef {
-                        object: s3::Key {
-                            bucket: settings.root.bucket.to_owned(),
-                            key: k,
-                        },
-                        updated: o.last_modified.unwrap(),
-                        reference: GitRef { name, sha },
-                    },
-                )
-            })
-        })
-        .into_group_map();
-    let refs: HashMap<String, RemoteRefs> = map
-        .into_iter()
-        .map(|(name, refs)| (name, sorted_remote_refs(refs)))
-        .collect();
-    Ok(refs)
-}
-
-fn sorted_remote_refs(refs: Vec<RemoteRef>) -> RemoteRefs {
-    RemoteRefs {
-        by_update_time: refs
-            .into_iter()
-            .sorted_by_key(|i| i.updated.to_owned())
-            .rev()
-            .collect(),
+fn cmd_list(remote: &Remote, options: &Options) -> Result<HashMap<String, RemoteRefs>> {
+    let refs = remote.list_refs()?;
+    // Comes first, so a clone can create its repository with it
+    if options.object_format {
+        if let Some(format) = remote.object_format(&refs) {
+            println!(":object-format {}", format);
+        }
     }
-}
-
-fn cmd_list(s3: &S3Client, settings: &Settings) -> Result<()> {
-    let refs = list_remote_refs(s3, settings)?;
     if !refs.is_empty() {
         for (_name, refs) in refs.iter() {
-            let mut iter = refs.by_update_time.iter();
+            let mut iter = refs.latest_first.iter();
             let latest = iter.next().unwrap();
             println!("{} {}", latest.reference.sha, latest.reference.name);
 
             for stale_ref in iter {
-                let short_sha = stale_ref.reference.sha.get(0..7).unwrap();
                 println!(
-                    "{} {}__{}",
-                    stale_ref.reference.sha, stale_ref.reference.name, short_sha
+                    "{} {}",
+                    stale_ref.reference.sha,
+                    stale_ref.reference.stale_name()
                 );
             }
         }
@@ -350,12 +327,15 @@ fn cmd_list(s3: &S3Client, settings: &Settings) -> Result<()> {
         }
     }
     println!();
-    Ok(())
+    Ok(refs)
 }
 
 fn cmd_capabilities() -> Result<()> {
     println!("*push");
     println!("*fetch");
+    println!("option");
+    println!("push-options");
+    println!("object-format");
     println!();
     Ok(())
 }
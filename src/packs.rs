@@ -0,0 +1,388 @@
+use chrono::Utc;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+
+use super::errors::*;
+use super::storage::{self, Storage};
+use super::{
+    config, git, is_sha, list_remote_refs, unbundle_object, upload_bundle, Encoding, GitRef,
+    RemoteRef, Settings,
+};
+
+// Remotes using packs (layout 3) store each part of the history once, as
+// <root>/packs/<id>.bundle: an encrypted bundle of the objects reachable from
+// its tips that the packs it builds on don't have. <root>/packs/<id>.json
+// lists those packs (and it, last), and a head is a manifest in the same
+// format at <root>/heads/<ref>/<sha>.json, so a branch only adds a pack for
+// the commits other branches haven't pushed, and a fetch only downloads the
+// packs of commits the repository is missing.
+const PACKS_DIR: &str = "packs";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackRef {
+    pub id: String,
+    // Commits the pack completes the history of
+    pub tips: Vec<String>,
+}
+
+// Packs that hold a commit's history, each after those it builds on
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub packs: Vec<PackRef>,
+}
+
+impl Manifest {
+    // Add the packs of another manifest that this one doesn't have yet
+    fn extend(&mut self, other: Manifest) {
+        for pack in other.packs {
+            if !self.packs.contains(&pack) {
+                self.packs.push(pack);
+            }
+        }
+    }
+}
+
+fn packs_prefix(settings: &Settings) -> storage::Key {
+    settings.dir_prefix(&format!("{}/{}", settings.root.key, PACKS_DIR))
+}
+
+pub fn pack_key(settings: &Settings, id: &str, extension: &str) -> storage::Key {
+    let prefix = packs_prefix(settings);
+    storage::Key {
+        bucket: prefix.bucket,
+        key: format!("{}{}.{}", prefix.key, id, extension),
+    }
+}
+
+pub fn read_manifest(s3: &dyn Storage, o: &storage::Key) -> Result<Manifest> {
+    let data = match s3.get_bytes(o)? {
+        Some(data) => data,
+        None => bail!(ErrorKind::NotFound(s3.url(o))),
+    };
+    serde_json::from_slice(&data).chain_err(|| format!("invalid manifest {}", s3.url(o)))
+}
+
+pub fn write_manifest(
+    s3: &dyn Storage,
+    o: &storage::Key,
+    manifest: &Manifest,
+    metadata: &HashMap<String, String>,
+) -> Result<()> {
+    let mut data = serde_json::to_vec_pretty(manifest).chain_err(|| "serialize manifest failed")?;
+    data.push(b'\n');
+    s3.put_bytes(data, o, metadata, None)
+}
+
+// Every pack on the remote. A pack's json is written after its bundle, so
+// only packs that are complete are listed. Packs written by gc come last,
+// newest last, so that pushes prefer them to the packs they replace.
+pub fn list_packs(s3: &dyn Storage, settings: &Settings) -> Result<Vec<PackRef>> {
+    let prefix = packs_prefix(settings);
+    let mut packs = vec![];
+    for o in s3.list(&prefix)? {
+        let id = match o
+            .key
+            .strip_prefix(&prefix.key)
+            .and_then(|k| k.strip_suffix(".json"))
+        {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        // Packs pushed for a commit are named after it, others say what they hold
+        let tips = if is_sha(&id) {
+            vec![id.to_owned()]
+        } else {
+            let manifest = read_manifest(s3, &pack_key(settings, &id, "json"))?;
+            match manifest.packs.into_iter().find(|p| p.id == id) {
+                Some(pack) => pack.tips,
+                None => continue,
+            }
+        };
+        packs.push(PackRef { id, tips });
+    }
+    packs.sort_by_key(|p| (!is_sha(&p.id), p.id.to_owned()));
+    Ok(packs)
+}
+
+// The packs that the manifest of some head uses
+pub fn referenced(s3: &dyn Storage, settings: &Settings) -> Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    for r in list_remote_refs(s3, settings)?
+        .values()
+        .flat_map(|rs| rs.latest_first.iter())
+    {
+        match read_manifest(s3, &r.object) {
+            Ok(manifest) => ids.extend(manifest.packs.into_iter().map(|p| p.id)),
+            // Replaced by a push since the remote was listed
+            Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ids)
+}
+
+// The json goes first, so the pack is no longer listed once it's incomplete
+pub fn delete(s3: &dyn Storage, settings: &Settings, id: &str) -> Result<()> {
+    s3.del(&pack_key(settings, id, "json"))?;
+    s3.del(&pack_key(settings, id, "bundle"))
+}
+
+// Replace the packs of the heads with one holding all of their history, and
+// point each head's manifest at it. Runs in a scratch repository, which the
+// heads are fetched into. The packs that were replaced are left for the
+// caller to delete.
+pub fn repack(
+    s3: &dyn Storage,
+    settings: &Settings,
+    heads: &[&RemoteRef],
+    encoding: &Encoding,
+) -> Result<PackRef> {
+    // What each head used, so heads that change during the repack are left
+    // alone when switching them over
+    let mut listed = vec![];
+    for r in heads {
+        if let Some(manifest) = read_manifest_if_exists(s3, &r.object)? {
+            listed.push((*r, manifest));
+        }
+    }
+    let tips = listed
+        .iter()
+        .map(|(r, _)| r.reference.sha.to_owned())
+        .sorted()
+        .dedup()
+        .collect_vec();
+    for (r, _) in listed.iter() {
+        fetch(s3, settings, &r.reference)?;
+    }
+    // A bundle holds the history of refs, rather than of commits
+    for sha in tips.iter() {
+        git::update_ref(&format!("refs/tips/{}", sha), sha)?;
+    }
+
+    let pack = PackRef {
+        id: format!("gc-{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")),
+        tips,
+    };
+    upload_bundle(
+        s3,
+        &pack_key(settings, &pack.id, "bundle"),
+        git::bundle_create_command("--all", &[]),
+        HashMap::new(),
+        encoding,
+    )?;
+    let manifest = Manifest {
+        packs: vec![pack.clone()],
+    };
+    write_manifest(
+        s3,
+        &pack_key(settings, &pack.id, "json"),
+        &manifest,
+        &HashMap::new(),
+    )?;
+
+    let listed = listed
+        .into_iter()
+        .map(|(r, m)| (&r.object, m))
+        .collect_vec();
+    switch_heads(s3, &listed, &manifest)?;
+    Ok(pack)
+}
+
+fn read_manifest_if_exists(s3: &dyn Storage, o: &storage::Key) -> Result<Option<Manifest>> {
+    match read_manifest(s3, o) {
+        Ok(manifest) => Ok(Some(manifest)),
+        Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Point heads at a manifest, keeping who pushed them. Heads that were deleted,
+// or pushed again with other packs, since they were listed are skipped rather
+// than brought back or reverted.
+fn switch_heads(
+    s3: &dyn Storage,
+    heads: &[(&storage::Key, Manifest)],
+    manifest: &Manifest,
+) -> Result<()> {
+    for (o, listed) in heads {
+        let metadata = match s3.head(o) {
+            Ok(head) => head.metadata,
+            Err(ref e) if matches!(e.typed_kind(), Some(ErrorKind::NotFound(_))) => continue,
+            Err(e) => return Err(e),
+        };
+        if read_manifest_if_exists(s3, o)?.as_ref() != Some(listed) {
+            continue;
+        }
+        write_manifest(s3, o, manifest, &metadata)?;
+    }
+    Ok(())
+}
+
+// Upload a pack of the commits the remote's packs don't have, unless a pack
+// already holds it, then the head's manifest
+pub fn push(
+    s3: &dyn Storage,
+    settings: &Settings,
+    r: &GitRef,
+    metadata: &HashMap<String, String>,
+    encoding: &Encoding,
+) -> Result<()> {
+    let packs = list_packs(s3, settings)?;
+    let manifest = match packs.iter().rev().find(|p| p.tips.contains(&r.sha)) {
+        Some(pack) => read_manifest(s3, &pack_key(settings, &pack.id, "json"))?,
+        None => push_pack(s3, settings, r, &packs, metadata, encoding)?,
+    };
+    write_manifest(s3, &settings.bundle_key(r), &manifest, metadata)
+}
+
+fn push_pack(
+    s3: &dyn Storage,
+    settings: &Settings,
+    r: &GitRef,
+    packs: &[PackRef],
+    metadata: &HashMap<String, String>,
+    encoding: &Encoding,
+) -> Result<Manifest> {
+    // The pack builds on the packs of the commit's ancestors, and only those
+    // that aren't ancestors of each other need to be left out of it. Later
+    // packs replace earlier ones with the same tip.
+    let by_tip: HashMap<&str, &PackRef> = packs
+        .iter()
+        .flat_map(|p| p.tips.iter().map(move |t| (t.as_str(), p)))
+        .collect();
+    let tips = by_tip.keys().copied().collect::<HashSet<_>>();
+    let base = git::independent(&git::ancestors_among(&r.sha, &tips)?)?;
+
+    let mut manifest = Manifest::default();
+    for sha in base.iter() {
+        let pack = by_tip[sha.as_str()];
+        manifest.extend(read_manifest(s3, &pack_key(settings, &pack.id, "json"))?);
+    }
+    let pack = PackRef {
+        id: r.sha.to_owned(),
+        tips: vec![r.sha.to_owned()],
+    };
+    let bundle = git::bundle_create_command(&r.name, &base);
+    upload_bundle(
+        s3,
+        &pack_key(settings, &pack.id, "bundle"),
+        bundle,
+        metadata.clone(),
+        encoding,
+    )?;
+    manifest.packs.push(pack);
+    write_manifest(
+        s3,
+        &pack_key(settings, &r.sha, "json"),
+        &manifest,
+        &HashMap::new(),
+    )?;
+    Ok(manifest)
+}
+
+// Download the packs of a head that hold commits the repository is missing.
+// Having a pack's tips means having all of its history (unless they are
+// shallow), so those are skipped.
+pub fn fetch(s3: &dyn Storage, settings: &Settings, r: &GitRef) -> Result<()> {
+    let manifest = read_manifest(s3, &settings.bundle_key(r))?;
+    let tips = manifest
+        .packs
+        .iter()
+        .flat_map(|p| p.tips.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    // The history of a shallow commit is missing, even though it isn't
+    let mut missing = git::missing_commits(&tips)?;
+    missing.extend(git::shallow_commits()?);
+    for pack in manifest
+        .packs
+        .iter()
+        .filter(|p| p.tips.iter().any(|t| missing.contains(t)))
+    {
+        unbundle_object(s3, &pack_key(settings, &pack.id, "bundle"), None)?;
+    }
+    Ok(())
+}
+
+// Commands that work on the bundles of heads can't be used on packs yet
+pub fn unsupported(settings: &Settings, command: &str) -> Result<()> {
+    if settings.layout() == config::LAYOUT_PACKS {
+        bail!(
+            "{} isn't supported on remotes using packs (layout {})",
+            command,
+            config::LAYOUT_PACKS
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(id: &str) -> PackRef {
+        PackRef {
+            id: id.to_string(),
+            tips: vec![id.to_string()],
+        }
+    }
+
+    #[test]
+    fn manifests_keep_the_first_of_each_pack() {
+        let mut manifest = Manifest {
+            packs: vec![pack("a"), pack("b")],
+        };
+        manifest.extend(Manifest {
+            packs: vec![pack("a"), pack("c"), pack("b")],
+        });
+        assert_eq!(manifest.packs, vec![pack("a"), pack("b"), pack("c")]);
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let manifest = Manifest {
+            packs: vec![pack("a")],
+        };
+        let data = serde_json::to_vec(&manifest).unwrap();
+        assert_eq!(serde_json::from_slice::<Manifest>(&data).unwrap(), manifest);
+    }
+
+    #[test]
+    fn only_unchanged_heads_are_switched() {
+        let dir = tempfile::Builder::new().prefix("packs").tempdir().unwrap();
+        let s3 = crate::file::FileStorage;
+        let key = |name: &str| storage::Key {
+            bucket: dir.path().to_string_lossy().to_string(),
+            key: format!("prefix/heads/refs/heads/{}/{}.json", name, name),
+        };
+        let (kept, deleted, pushed) = (key("kept"), key("deleted"), key("pushed"));
+        let old = || Manifest {
+            packs: vec![pack("a")],
+        };
+        let metadata = [("pusher-name".to_string(), "someone".to_string())]
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        for o in [&kept, &deleted, &pushed].iter() {
+            write_manifest(&s3, o, &old(), &metadata).unwrap();
+        }
+        let listed = vec![(&kept, old()), (&deleted, old()), (&pushed, old())];
+
+        // Changed after the heads were listed
+        s3.del(&deleted).unwrap();
+        let other = Manifest {
+            packs: vec![pack("a"), pack("b")],
+        };
+        write_manifest(&s3, &pushed, &other, &HashMap::new()).unwrap();
+
+        let repacked = Manifest {
+            packs: vec![pack("gc")],
+        };
+        switch_heads(&s3, &listed, &repacked).unwrap();
+        assert_eq!(read_manifest(&s3, &kept).unwrap(), repacked);
+        assert_eq!(s3.head(&kept).unwrap().metadata, metadata);
+        assert!(read_manifest_if_exists(&s3, &deleted).unwrap().is_none());
+        assert_eq!(read_manifest(&s3, &pushed).unwrap(), other);
+    }
+}
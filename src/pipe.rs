@@ -0,0 +1,189 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+
+use super::errors::*;
+
+// A command in a pipeline, and what its failure is reported as
+pub struct Stage {
+    command: Command,
+    failure: Option<ErrorKind>,
+}
+
+impl Stage {
+    pub fn new<K: Into<ErrorKind>>(command: Command, failure: K) -> Stage {
+        Stage {
+            command,
+            failure: Some(failure.into()),
+        }
+    }
+}
+
+struct Running {
+    child: Child,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+    status: Option<ExitStatus>,
+    failure: Option<ErrorKind>,
+    description: String,
+}
+
+// Commands with the output of each connected to the input of the next, like a
+// shell pipeline, so data streams through them without temporary files and
+// only a pipe's worth of it is held at a time.
+pub struct Pipeline {
+    running: Vec<Running>,
+    input: Option<JoinHandle<io::Result<u64>>>,
+    output: Option<ChildStdout>,
+    // Whether the output was read to the end, as when it wasn't, the
+    // commands fail because nothing is reading it
+    eof: bool,
+}
+
+impl Pipeline {
+    // The first command reads `input` (copied on another thread), and the
+    // output of the last is read from the pipeline, or discarded if
+    // `output` is false. The commands' stderr is only shown if they fail.
+    pub fn spawn(
+        stages: Vec<Stage>,
+        input: Option<Box<dyn Read + Send>>,
+        output: bool,
+    ) -> Result<Pipeline> {
+        let mut running: Vec<Running> = vec![];
+        let mut stdin = None;
+        let count = stages.len();
+        for (idx, mut stage) in stages.into_iter().enumerate() {
+            let command = &mut stage.command;
+            let from_previous = running.last_mut().and_then(|r| r.child.stdout.take());
+            match from_previous {
+                Some(stdout) => command.stdin(Stdio::from(stdout)),
+                None if input.is_some() => command.stdin(Stdio::piped()),
+                None => command.stdin(Stdio::null()),
+            };
+            if idx + 1 < count || output {
+                command.stdout(Stdio::piped());
+            } else {
+                command.stdout(Stdio::null());
+            }
+            command.stderr(Stdio::piped());
+            let description = format!("{:?}", command);
+            let mut child = command
+                .spawn()
+                .chain_err(|| format!("failed to run {}", description))?;
+            if idx == 0 {
+                stdin = child.stdin.take();
+            }
+            let stderr = child.stderr.take().map(|mut stderr| {
+                thread::spawn(move || {
+                    let mut contents = vec![];
+                    let _ = stderr.read_to_end(&mut contents);
+                    contents
+                })
+            });
+            running.push(Running {
+                child,
+                stderr,
+                status: None,
+                failure: stage.failure,
+                description,
+            });
+        }
+
+        let input = match (input, stdin) {
+            (Some(mut input), Some(mut stdin)) => Some(thread::spawn(move || {
+                let copied = io::copy(&mut input, &mut stdin);
+                // Closing stdin lets the command see the end of its input
+                drop(stdin);
+                copied
+            })),
+            _ => None,
+        };
+        let output = if output {
+            running.last_mut().and_then(|r| r.child.stdout.take())
+        } else {
+            None
+        };
+        Ok(Pipeline {
+            running,
+            input,
+            output,
+            eof: false,
+        })
+    }
+
+    // Wait for the commands to exit, true if they all succeeded
+    fn wait(&mut self) -> io::Result<bool> {
+        let mut succeeded = true;
+        for r in self.running.iter_mut() {
+            let status = match r.status {
+                Some(status) => status,
+                None => r.child.wait()?,
+            };
+            r.status = Some(status);
+            succeeded &= status.success();
+        }
+        Ok(succeeded)
+    }
+
+    // Wait for every command, failing with the first that failed. A failed
+    // input (e.g. a download) is reported before the commands, which fail
+    // as a result of it.
+    pub fn finish(mut self) -> Result<()> {
+        // Nothing will read the rest of the output, so let the commands see
+        // that rather than block writing it
+        let complete = self.eof || self.output.is_none();
+        self.output.take();
+        if !complete {
+            for r in self.running.iter_mut() {
+                let _ = r.child.kill();
+            }
+        }
+        self.wait().chain_err(|| "wait failed")?;
+
+        if let Some(input) = self.input.take() {
+            match input.join() {
+                Ok(Ok(_)) => (),
+                // The command exited without reading all of it, which is
+                // reported below
+                Ok(Err(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                Ok(Err(e)) => return Err(e).chain_err(|| "read failed"),
+                Err(_) => bail!("input thread panicked"),
+            }
+        }
+
+        let mut failure = None;
+        for r in self.running.iter_mut() {
+            let stderr = r
+                .stderr
+                .take()
+                .and_then(|h| h.join().ok())
+                .unwrap_or_default();
+            if failure.is_none() && complete && r.status.is_some_and(|s| !s.success()) {
+                eprintln!("Failed command: {}", r.description);
+                io::stderr().write_all(&stderr).unwrap();
+                failure = r.failure.take();
+            }
+        }
+        match failure {
+            Some(kind) => bail!(kind),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for Pipeline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.output.as_mut() {
+            Some(output) => output.read(buf)?,
+            None => 0,
+        };
+        if n == 0 && !buf.is_empty() && !self.eof {
+            self.eof = true;
+            // The output ends early if a command fails, which mustn't be
+            // mistaken for the end of the data
+            if !self.wait()? {
+                return Err(io::Error::other("command failed"));
+            }
+        }
+        Ok(n)
+    }
+}
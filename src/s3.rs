@@ -1,76 +1,368 @@
 extern crate rusoto_core;
 extern crate rusoto_s3;
 
+use rusoto_core::RusotoError;
 use rusoto_s3::{
-    DeleteObjectOutput, DeleteObjectRequest, GetObjectOutput, GetObjectRequest,
-    ListObjectsV2Output, ListObjectsV2Request, PutObjectOutput, PutObjectRequest, S3Client, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectError, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest,
+    S3Client, UploadPartRequest, S3,
 };
 
-use std::fs::{File, OpenOptions};
-use std::io::{copy, Read};
-use std::path::Path;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io::Read;
 
 use super::errors::*;
+use super::storage::{Download, Head, Key, Object, Storage};
 
-#[derive(Debug)]
-pub struct Key {
-    pub bucket: String,
-    pub key: String,
+// The size of each part of a multipart upload, s3's minimum is 5MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+pub struct S3Storage {
+    client: S3Client,
 }
 
-pub fn get(s3: &S3Client, o: &Key, f: &Path) -> Result<GetObjectOutput> {
-    let req = GetObjectRequest {
-        bucket: o.bucket.to_owned(),
-        key: o.key.to_owned(),
-        ..Default::default()
-    };
-    let mut result = s3
-        .get_object(req)
-        .sync()
-        .chain_err(|| "couldn't get item")?;
-    let body = result.body.take().chain_err(|| "no body")?;
-    let mut target = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(f)
-        .chain_err(|| "open failed")?;
-    copy(&mut body.into_blocking_read(), &mut target).chain_err(|| "copy failed")?;
-    Ok(result)
+impl S3Storage {
+    pub fn new(client: S3Client) -> S3Storage {
+        S3Storage { client }
+    }
 }
 
-pub fn put(s3: &S3Client, f: &Path, o: &Key) -> Result<PutObjectOutput> {
-    let mut f = File::open(f).chain_err(|| "open failed")?;
-    let mut contents: Vec<u8> = Vec::new();
-    f.read_to_end(&mut contents).chain_err(|| "read failed")?;
-    let req = PutObjectRequest {
-        bucket: o.bucket.to_owned(),
-        key: o.key.to_owned(),
-        body: Some(contents.into()),
-        ..Default::default()
-    };
-    s3.put_object(req)
-        .sync()
-        .chain_err(|| "Couldn't PUT object")
+impl Storage for S3Storage {
+    fn scheme(&self) -> &'static str {
+        "s3"
+    }
+
+    fn get_reader(&self, o: &Key) -> Result<Download> {
+        let req = GetObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            ..Default::default()
+        };
+        let mut result = self
+            .client
+            .get_object(req)
+            .sync()
+            .map_err(|e| get_error(e, o, "couldn't get item"))?;
+        let body = result.body.take().chain_err(|| "no body")?;
+        Ok(Download {
+            body: Box::new(body.into_blocking_read()),
+            metadata: result.metadata.unwrap_or_default(),
+        })
+    }
+
+    fn get_bytes(&self, o: &Key) -> Result<Option<Vec<u8>>> {
+        let req = GetObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            ..Default::default()
+        };
+        let mut result = match self.client.get_object(req).sync() {
+            Ok(result) => result,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(e) => return Err(get_error(e, o, "couldn't get item")),
+        };
+        let body = result.body.take().chain_err(|| "no body")?;
+        let mut contents = Vec::new();
+        body.into_blocking_read()
+            .read_to_end(&mut contents)
+            .chain_err(|| "read failed")?;
+        Ok(Some(contents))
+    }
+
+    fn get_head_bytes(&self, o: &Key, len: usize) -> Result<Vec<u8>> {
+        let req = GetObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            range: Some(format!("bytes=0-{}", len - 1)),
+            ..Default::default()
+        };
+        let mut result = self
+            .client
+            .get_object(req)
+            .sync()
+            .map_err(|e| get_error(e, o, "couldn't get item"))?;
+        let body = result.body.take().chain_err(|| "no body")?;
+        let mut contents = Vec::new();
+        body.into_blocking_read()
+            .take(len as u64)
+            .read_to_end(&mut contents)
+            .chain_err(|| "read failed")?;
+        Ok(contents)
+    }
+
+    fn put_bytes(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let req = PutObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            body: Some(contents.into()),
+            metadata: Some(metadata.to_owned()),
+            storage_class: storage_class.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        self.client
+            .put_object(req)
+            .sync()
+            .map_err(|e| s3_error(e, o, "Couldn't PUT object"))?;
+        Ok(())
+    }
+
+    // Objects larger than a part are uploaded a part at a time, so only one
+    // part is held in memory
+    fn put_reader(
+        &self,
+        reader: &mut dyn Read,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let first = read_part(reader)?;
+        if first.len() < PART_SIZE {
+            return self.put_bytes(first, o, metadata, storage_class);
+        }
+
+        let req = CreateMultipartUploadRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            metadata: Some(metadata.to_owned()),
+            storage_class: storage_class.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        let upload_id = self
+            .client
+            .create_multipart_upload(req)
+            .sync()
+            .map_err(|e| s3_error(e, o, "Couldn't start multipart upload"))?
+            .upload_id
+            .chain_err(|| "no upload id")?;
+        let parts = match self.put_parts(first, reader, o, &upload_id) {
+            Ok(parts) => parts,
+            Err(e) => {
+                // Otherwise the parts are kept (and charged for) indefinitely
+                let req = AbortMultipartUploadRequest {
+                    bucket: o.bucket.to_owned(),
+                    key: o.key.to_owned(),
+                    upload_id,
+                    ..Default::default()
+                };
+                let _ = self.client.abort_multipart_upload(req).sync();
+                return Err(e);
+            }
+        };
+        let req = CompleteMultipartUploadRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            upload_id,
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+        self.client
+            .complete_multipart_upload(req)
+            .sync()
+            .map_err(|e| s3_error(e, o, "Couldn't complete multipart upload"))?;
+        Ok(())
+    }
+
+    // The s3 api used has no conditional put, so this only narrows the window
+    // in which a concurrent put is overwritten (see Storage)
+    fn put_if_unseen(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+    ) -> Result<bool> {
+        let req = HeadObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            ..Default::default()
+        };
+        match self.client.head_object(req).sync() {
+            Ok(_) => return Ok(false),
+            // HEAD responses have no body, so a missing key is an unknown error
+            Err(RusotoError::Unknown(ref r)) if r.status.as_u16() == 404 => (),
+            Err(e) => return Err(s3_error(e, o, "Couldn't HEAD object")),
+        }
+        self.put_bytes(contents, o, metadata, None)?;
+        Ok(true)
+    }
+
+    fn head(&self, o: &Key) -> Result<Head> {
+        let req = HeadObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            ..Default::default()
+        };
+        let head = self
+            .client
+            .head_object(req)
+            .sync()
+            .map_err(|e| s3_error(e, o, "Couldn't HEAD object"))?;
+        Ok(Head {
+            metadata: head.metadata.unwrap_or_default(),
+            storage_class: head.storage_class,
+        })
+    }
+
+    fn copy(
+        &self,
+        from: &Key,
+        to: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let req = CopyObjectRequest {
+            bucket: to.bucket.to_owned(),
+            key: to.key.to_owned(),
+            copy_source: format!("{}/{}", from.bucket, encode_key(&from.key)),
+            metadata: Some(metadata.to_owned()),
+            metadata_directive: Some("REPLACE".to_string()),
+            storage_class: storage_class.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        self.client
+            .copy_object(req)
+            .sync()
+            .map_err(|e| s3_error(e, from, "Couldn't COPY object"))?;
+        Ok(())
+    }
+
+    fn del(&self, o: &Key) -> Result<()> {
+        let req = DeleteObjectRequest {
+            bucket: o.bucket.to_owned(),
+            key: o.key.to_owned(),
+            ..Default::default()
+        };
+        self.client
+            .delete_object(req)
+            .sync()
+            .map_err(|e| s3_error(e, o, "Couldn't DELETE object"))?;
+        Ok(())
+    }
+
+    // Follows continuation tokens past the 1000 objects s3 returns per request
+    fn list(&self, prefix: &Key) -> Result<Vec<Object>> {
+        let mut objects = vec![];
+        let mut continuation_token = None;
+        loop {
+            let list_obj_req = ListObjectsV2Request {
+                bucket: prefix.bucket.to_owned(),
+                prefix: Some(prefix.key.to_owned()),
+                continuation_token,
+                ..Default::default()
+            };
+            let result = self
+                .client
+                .list_objects_v2(list_obj_req)
+                .sync()
+                .map_err(|e| s3_error(e, prefix, "Couldn't list items in bucket"))?;
+            objects.extend(
+                result
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|o| {
+                        Some(Object {
+                            key: o.key?,
+                            last_modified: o.last_modified.unwrap_or_default(),
+                            size: o.size.unwrap_or_default(),
+                        })
+                    }),
+            );
+            continuation_token = result.next_continuation_token;
+            if !result.is_truncated.unwrap_or(false) || continuation_token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
 }
 
-pub fn del(s3: &S3Client, o: &Key) -> Result<DeleteObjectOutput> {
-    let req = DeleteObjectRequest {
-        bucket: o.bucket.to_owned(),
-        key: o.key.to_owned(),
-        ..Default::default()
-    };
-    s3.delete_object(req)
-        .sync()
-        .chain_err(|| "Couldn't DELETE object")
+impl S3Storage {
+    fn put_parts(
+        &self,
+        first: Vec<u8>,
+        reader: &mut dyn Read,
+        o: &Key,
+        upload_id: &str,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut parts = vec![];
+        let mut part = first;
+        while !part.is_empty() {
+            let part_number = parts.len() as i64 + 1;
+            let req = UploadPartRequest {
+                bucket: o.bucket.to_owned(),
+                key: o.key.to_owned(),
+                upload_id: upload_id.to_string(),
+                part_number,
+                body: Some(part.into()),
+                ..Default::default()
+            };
+            let result = self
+                .client
+                .upload_part(req)
+                .sync()
+                .map_err(|e| s3_error(e, o, "Couldn't upload part"))?;
+            parts.push(CompletedPart {
+                e_tag: result.e_tag,
+                part_number: Some(part_number),
+            });
+            part = read_part(reader)?;
+        }
+        Ok(parts)
+    }
+}
+
+// Up to a part's worth, less only at the end
+fn read_part(reader: &mut dyn Read) -> Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    reader
+        .take(PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .chain_err(|| "read failed")?;
+    Ok(part)
 }
 
-pub fn list(s3: &S3Client, k: &Key) -> Result<ListObjectsV2Output> {
-    let list_obj_req = ListObjectsV2Request {
-        bucket: k.bucket.to_owned(),
-        prefix: Some(k.key.to_owned()),
-        ..Default::default()
+// Access denied and missing objects are told apart from other failures, so
+// they can be reported to git. Most operations don't model these errors, and
+// HEAD responses have no body to say which it was, so the status is used.
+fn s3_error<E: StdError + Send + 'static>(e: RusotoError<E>, o: &Key, what: &str) -> Error {
+    let status = match &e {
+        RusotoError::Unknown(r) => Some(r.status.as_u16()),
+        _ => None,
     };
-    s3.list_objects_v2(list_obj_req)
-        .sync()
-        .chain_err(|| "Couldn't list items in bucket")
+    let url = format!("s3://{}/{}", o.bucket, o.key);
+    match status {
+        Some(403) => Error::with_chain(e, ErrorKind::AccessDenied(url)),
+        Some(404) => Error::with_chain(e, ErrorKind::NotFound(url)),
+        _ => Error::with_chain(e, what),
+    }
+}
+
+// GET is the one operation whose missing keys are a modelled error
+fn get_error(e: RusotoError<GetObjectError>, o: &Key, what: &str) -> Error {
+    match e {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+            let url = format!("s3://{}/{}", o.bucket, o.key);
+            Error::with_chain(e, ErrorKind::NotFound(url))
+        }
+        e => s3_error(e, o, what),
+    }
+}
+
+// The copy source header is url encoded, which rusoto leaves to the caller
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
 }
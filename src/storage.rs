@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::Path;
+
+use super::errors::*;
+
+// An object in a bucket. For the file backend, the bucket is a directory.
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub bucket: String,
+    pub key: String,
+}
+
+// An object as listed
+#[derive(Debug)]
+pub struct Object {
+    pub key: String,
+    // RFC 3339, e.g. 2019-12-24T10:01:02.000Z
+    pub last_modified: String,
+    pub size: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Head {
+    pub metadata: HashMap<String, String>,
+    pub storage_class: Option<String>,
+}
+
+// An object being read, and its metadata
+pub struct Download {
+    pub body: Box<dyn Read + Send>,
+    pub metadata: HashMap<String, String>,
+}
+
+// Where bundles are kept. Metadata is a set of string pairs stored with each
+// object, and the storage class is only meaningful to s3. Objects may be
+// uploaded from several threads at once.
+pub trait Storage: Sync {
+    // The url scheme remotes using this storage have
+    fn scheme(&self) -> &'static str;
+
+    fn url(&self, k: &Key) -> String {
+        format!("{}://{}/{}", self.scheme(), k.bucket, k.key)
+    }
+
+    // Stream an object, rather than holding all of it
+    fn get_reader(&self, o: &Key) -> Result<Download>;
+
+    fn get(&self, o: &Key, f: &Path) -> Result<()> {
+        let mut source = self.get_reader(o)?.body;
+        let mut target = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(f)
+            .chain_err(|| "open failed")?;
+        io::copy(&mut source, &mut target).chain_err(|| "copy failed")?;
+        Ok(())
+    }
+
+    // Read a whole (small) object, None if it doesn't exist
+    fn get_bytes(&self, o: &Key) -> Result<Option<Vec<u8>>>;
+
+    // Read the start of an object, up to `len` bytes
+    fn get_head_bytes(&self, o: &Key, len: usize) -> Result<Vec<u8>>;
+
+    fn put(
+        &self,
+        f: &Path,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()> {
+        let mut f = File::open(f).chain_err(|| "open failed")?;
+        self.put_reader(&mut f, o, metadata, storage_class)
+    }
+
+    // Upload everything read, holding a bounded amount of it at a time. If
+    // reading fails, no object is created.
+    fn put_reader(
+        &self,
+        reader: &mut dyn Read,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()>;
+
+    fn put_bytes(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()>;
+
+    // Put an object unless one is seen to exist already, false if it was. Only
+    // a best effort: the file backend checks atomically, but on s3 a put made
+    // at the same time can still be overwritten, so callers read back what
+    // they rely on.
+    fn put_if_unseen(
+        &self,
+        contents: Vec<u8>,
+        o: &Key,
+        metadata: &HashMap<String, String>,
+    ) -> Result<bool>;
+
+    fn head(&self, o: &Key) -> Result<Head>;
+
+    // Copy an object without downloading it. The metadata and storage class
+    // of the copy have to be given again.
+    fn copy(
+        &self,
+        from: &Key,
+        to: &Key,
+        metadata: &HashMap<String, String>,
+        storage_class: Option<&str>,
+    ) -> Result<()>;
+
+    fn del(&self, o: &Key) -> Result<()>;
+
+    // Every object whose key starts with the prefix
+    fn list(&self, prefix: &Key) -> Result<Vec<Object>>;
+}
@@ -1,22 +1,32 @@
 extern crate assert_cmd;
 extern crate rusoto_s3;
 
-//use git_remote_s3;
 use rusoto_core::{HttpClient, Region};
 use rusoto_credential::StaticProvider;
-use rusoto_s3::{
-    CreateBucketRequest, DeleteBucketRequest, DeleteObjectRequest, ListObjectsV2Request, S3Client,
-    S3,
-};
+use rusoto_s3::{CreateBucketRequest, ListObjectsV2Request, S3Client, S3};
 
 use tempfile::Builder;
 
+use git_remote_s3::{Config, Remote};
+
 use assert_cmd::cargo::cargo_bin;
 use assert_cmd::prelude::*;
 use std::env;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+mod support;
+
+use support::FakeS3;
+
+// One server for every test, each using its own bucket
+fn server() -> &'static FakeS3 {
+    static SERVER: OnceLock<FakeS3> = OnceLock::new();
+    SERVER.get_or_init(FakeS3::start)
+}
 
 fn git(pwd: &Path, args: &str) -> Command {
     let my_path = cargo_bin("git-remote-s3");
@@ -27,13 +37,24 @@ fn git(pwd: &Path, args: &str) -> Command {
     let mut command = Command::new("git");
     command.current_dir(pwd);
     command.env("PATH", new_path);
-    command.env("S3_ENDPOINT", "http://localhost:9001");
+    command.env("S3_ENDPOINT", server().endpoint());
     command.env("AWS_ACCESS_KEY_ID", "test");
     command.env("AWS_SECRET_ACCESS_KEY", "test1234");
     cmd_args(&mut command, args);
     command
 }
 
+fn admin(pwd: &Path, args: &str) -> Command {
+    let mut command = Command::cargo_bin("git-remote-s3").unwrap();
+    command.current_dir(pwd);
+    command.env("S3_ENDPOINT", server().endpoint());
+    command.env("AWS_ACCESS_KEY_ID", "test");
+    command.env("AWS_SECRET_ACCESS_KEY", "test1234");
+    command.arg("admin");
+    cmd_args(&mut command, args);
+    command
+}
+
 fn cmd_args(command: &mut Command, args: &str) {
     let words: Vec<_> = args.split_whitespace().collect();
 
@@ -42,20 +63,6 @@ fn cmd_args(command: &mut Command, args: &str) {
     }
 }
 
-fn delete_object(client: &S3Client, bucket: &str, filename: &str) {
-    let del_req = DeleteObjectRequest {
-        bucket: bucket.to_owned(),
-        key: filename.to_owned(),
-        ..Default::default()
-    };
-
-    let result = client
-        .delete_object(del_req)
-        .sync()
-        .expect("Couldn't delete object");
-    println!("{:#?}", result);
-}
-
 fn list_keys_in_bucket(client: &S3Client, bucket: &str) -> Vec<String> {
     let list_obj_req = ListObjectsV2Request {
         bucket: bucket.to_owned(),
@@ -86,23 +93,6 @@ fn create_bucket(client: &S3Client, bucket: &str) {
     println!("{:?}", result);
 }
 
-fn delete_bucket(client: &S3Client, bucket: &str) {
-    let delete_bucket_req = DeleteBucketRequest {
-        bucket: bucket.to_owned(),
-    };
-
-    let result = client.delete_bucket(delete_bucket_req).sync();
-    println!("{:?}", result);
-}
-
-fn delete_bucket_recurse(client: &S3Client, bucket: &str) {
-    let keys = list_keys_in_bucket(client, bucket);
-    for k in keys {
-        delete_object(client, bucket, &k);
-    }
-    delete_bucket(client, bucket);
-}
-
 fn git_rev(pwd: &Path) -> String {
     let out = git(pwd, "rev-parse --short HEAD").output().unwrap();
     String::from_utf8(out.stdout).unwrap().trim().to_string()
@@ -117,7 +107,7 @@ fn git_rev_long(pwd: &Path) -> String {
 fn integration() {
     let region = Region::Custom {
         name: "us-east-1".to_owned(),
-        endpoint: "http://localhost:9001".to_owned(),
+        endpoint: server().endpoint().to_owned(),
     };
 
     let s3 = S3Client::new_with(
@@ -139,9 +129,9 @@ fn integration() {
     fs::create_dir(&repo1).unwrap();
     fs::create_dir(&repo2).unwrap();
 
-    // Setup s3 bucket
-    delete_bucket_recurse(&s3, "git-remote-s3");
+    // Setup s3 bucket, listing a few objects at a time to exercise pagination
     create_bucket(&s3, "git-remote-s3");
+    server().set_page_size(2);
 
     println!("test: pushing from repo1");
     git(&repo1, "init").assert().success();
@@ -180,10 +170,18 @@ fn integration() {
         .assert()
         .stdout(format!("{} (HEAD -> master, origin/master) r2_c1\n", sha));
 
-    println!("test: force push form repo2");
+    println!("test: failed upload leaves the remote unchanged");
+    let shal = git_rev_long(&repo1);
     git(&repo1, "commit --allow-empty -am r1_c2")
         .assert()
         .success();
+    server().fail("PUT", ".bundle", 500, "InternalError", 1);
+    git(&repo1, "push origin").assert().failure();
+    git(&repo1, "ls-remote origin")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\tHEAD\n", shal, shal));
+
+    println!("test: force push form repo2");
     git(&repo1, "push origin").assert().success();
     let sha1 = git_rev(&repo1);
     let sha1l = git_rev_long(&repo1);
@@ -193,10 +191,19 @@ fn integration() {
     let sha2 = git_rev(&repo2);
     let sha2l = git_rev_long(&repo2);
     git(&repo2, "push origin").assert().failure();
-    git(&repo2, "push -f origin").assert().success();
+    let out = git(&repo2, "push -f origin").output().unwrap();
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("warning: refs/heads/master has 2 heads on s3"));
+    assert!(stderr.contains(&format!("warning:   stale head {}", sha1l)));
+    assert!(stderr.contains(&format!("warning:   git pull origin master__{}", sha1)));
     // assert that there are 2 refs on s3 (the original was kept)
     git(&repo1, "ls-remote origin").assert()
         .stdout(format!("{}\trefs/heads/master\n{}\trefs/heads/master__{}\n{}\tHEAD\n", sha2l, sha1l, sha1, sha2l));
+    let out = git(&repo1, "fetch origin").output().unwrap();
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains(&format!("warning:   stale head {}", sha1l)));
     git(&repo1, "pull -r origin master").assert().success();
     git(
         &repo1,
@@ -208,4 +215,878 @@ fn integration() {
     // assert that refs are unchanged on s3
     git(&repo1, "ls-remote origin").assert()
         .stdout(format!("{}\trefs/heads/master\n{}\trefs/heads/master__{}\n{}\tHEAD\n", sha2l, sha1l, sha1, sha2l));
+
+    println!("test: prune only deletes unmerged heads with --force");
+    admin(&repo1, "prune origin --older-than 0s --yes")
+        .assert()
+        .failure();
+    git(&repo1, "ls-remote origin").assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\trefs/heads/master__{}\n{}\tHEAD\n", sha2l, sha1l, sha1, sha2l));
+
+    println!("test: prune keeps unmerged heads");
+    let out = admin(&repo1, "prune origin --force --older-than 1d --yes")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let out = String::from_utf8(out.stdout).unwrap();
+    assert!(out.contains(&format!("{} refs/heads/master__{}", sha1l, sha1)));
+    assert!(out.contains("keep (not merged)"));
+    assert!(out.ends_with("nothing to prune\n"));
+
+    println!("test: force push with lease");
+    git(&repo2, "pull origin master").assert().success();
+    git(&repo2, "commit --allow-empty -am r2_c3")
+        .assert()
+        .success();
+    git(&repo2, "push origin master").assert().success();
+    git(&repo2, "commit --amend --allow-empty -m r2_c3_amended")
+        .assert()
+        .success();
+    git(&repo2, "push --force-with-lease origin master")
+        .assert()
+        .success();
+    let sha3l = git_rev_long(&repo2);
+    git(&repo1, "ls-remote origin refs/heads/master")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n", sha3l));
+    // repo1 has not fetched r2_c3_amended, so its lease is stale
+    git(&repo1, "commit --allow-empty -am r1_c3")
+        .assert()
+        .success();
+    git(&repo1, "push --force-with-lease origin master")
+        .assert()
+        .failure();
+    git(&repo1, "ls-remote origin refs/heads/master")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n", sha3l));
+
+    println!("test: force push without keeping stale heads");
+    git(
+        &repo1,
+        "push -o keep-stale=false -o storage-class=STANDARD -o message=cleanup -f origin master",
+    )
+    .assert()
+    .success();
+    let sha4l = git_rev_long(&repo1);
+    let out = admin(&repo1, "heads origin master").output().unwrap();
+    let out = String::from_utf8(out.stdout).unwrap();
+    assert!(out.starts_with(&format!("{} refs/heads/master (latest)\n", sha4l)));
+    assert!(out.contains("    message: cleanup\n"));
+    assert!(out.contains("    pusher-email: test@example.com\n"));
+    assert!(out.contains(&format!("    parent-sha: {}\n", sha3l)));
+    let out = admin(&repo1, "ls origin").output().unwrap();
+    let out = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(out.lines().count(), 1);
+    assert!(out.starts_with(&format!("{}\trefs/heads/master\t", sha4l)));
+    let out = admin(&repo1, "verify origin").output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("verified 1 objects, 0 problems\n"));
+    git(&repo2, "ls-remote origin").assert().stdout(format!(
+        "{}\trefs/heads/master\n{}\tHEAD\n",
+        sha4l, sha4l
+    ));
+    assert_eq!(list_keys_in_bucket(&s3, "git-remote-s3").len(), 1);
+
+    println!("test: rekey");
+    git(&repo1, "config remote.origin.gpgRecipients")
+        .arg("test@example.com second@example.com")
+        .assert()
+        .success();
+    // Data that wasn't re-encrypted is rekeyed whatever its metadata says
+    server().set_metadata(
+        "git-remote-s3",
+        "test/refs/heads/master/",
+        "recipients",
+        "second@example.com test@example.com",
+    );
+    server().fail("PUT", "test/refs/heads/master/", 403, "AccessDenied", 1);
+    admin(&repo1, "rekey origin --yes").assert().failure();
+    let out = admin(&repo1, "rekey origin --yes").output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with(".bundle: re-encrypted\n"));
+    let out = admin(&repo1, "rekey origin --yes").output().unwrap();
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("nothing to rekey\n"));
+    admin(&repo1, "verify origin").assert().success();
+    let out = admin(&repo1, "recipients origin").output().unwrap();
+    let out = String::from_utf8(out.stdout).unwrap();
+    assert!(out.starts_with(&format!("{} refs/heads/master\n", sha4l)));
+    assert!(out.contains("<test@example.com>"));
+    assert!(!out.contains("!"));
+
+    git(&repo1, "commit --allow-empty -am r1_c4")
+        .assert()
+        .success();
+    git(&repo1, "push -o unknown-option origin master")
+        .assert()
+        .failure();
+
+    println!("test: init");
+    admin(&repo1, "init origin --recipients=test@example.com")
+        .assert()
+        .success();
+    admin(&repo1, "init origin").assert().failure();
+    // repo1's gpgRecipients now disagree with the remote's
+    git(&repo1, "push origin master").assert().failure();
+    git(&repo1, "config --unset remote.origin.gpgRecipients")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    let out = admin(&repo1, "recipients origin").output().unwrap();
+    assert!(!String::from_utf8(out.stdout).unwrap().contains("!"));
+    git(&repo2, "fetch origin").assert().success();
+    admin(&repo1, "verify origin").assert().success();
+
+    println!("test: migrate");
+    let out = admin(&repo1, "migrate origin --yes").output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("to layout 2\n"));
+    assert!(list_keys_in_bucket(&s3, "git-remote-s3")
+        .iter()
+        .all(|k| k.starts_with("test/bundles/") || k.ends_with(".json")));
+    git(&repo1, "commit --allow-empty -am r1_c5")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    let sha5l = git_rev_long(&repo1);
+    git(&repo2, "ls-remote origin").assert().stdout(format!(
+        "{}\trefs/heads/master\n{}\tHEAD\n",
+        sha5l, sha5l
+    ));
+    git(&repo2, "fetch origin").assert().success();
+    admin(&repo1, "verify origin").assert().success();
+
+    println!("test: mirror");
+    let out = admin(&repo1, "mirror origin s3://git-remote-s3/mirror")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("mirrored 1 heads to s3://git-remote-s3/mirror\n"));
+    admin(&repo1, "verify s3://git-remote-s3/mirror")
+        .assert()
+        .success();
+    let out = admin(&repo1, "mirror origin s3://git-remote-s3/mirror")
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("mirrored 0 heads to s3://git-remote-s3/mirror\n"));
+    git(&repo2, "ls-remote s3://git-remote-s3/mirror")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\tHEAD\n", sha5l, sha5l));
+
+    println!("test: export and import");
+    let export = test_dir.path().join("export.git");
+    admin(&repo1, &format!("export origin {}", export.display()))
+        .assert()
+        .success();
+    admin(&repo1, &format!("export origin {}", export.display()))
+        .assert()
+        .failure();
+    admin(
+        &repo1,
+        &format!("import {} s3://git-remote-s3/imported", export.display()),
+    )
+    .assert()
+    .success();
+    git(&repo2, "ls-remote s3://git-remote-s3/imported")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\tHEAD\n", sha5l, sha5l));
+    admin(&repo1, "verify s3://git-remote-s3/imported")
+        .assert()
+        .success();
+
+    println!("test: push errors are reported per ref");
+    git(&repo1, "checkout -b feature").assert().success();
+    git(&repo1, "commit --allow-empty -am r1_feature")
+        .assert()
+        .success();
+    let feature = git_rev_long(&repo1);
+    git(&repo1, "checkout master").assert().success();
+    git(&repo1, "commit --allow-empty -am r1_c6")
+        .assert()
+        .success();
+    let sha6l = git_rev_long(&repo1);
+    server().fail("PUT", "refs/heads/feature/", 403, "AccessDenied", 1);
+    let out = git(&repo1, "push origin master feature").output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("access denied to s3://git-remote-s3/test/bundles/refs/heads/feature/"));
+    git(&repo2, "ls-remote origin")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\tHEAD\n", sha6l, sha6l));
+    git(&repo1, "push origin feature").assert().success();
+
+    println!("test: cloning several refs");
+    let repo3 = test_dir.path().join("repo3");
+    let listings = server().listings("git-remote-s3");
+    git(
+        test_dir.path(),
+        &format!("clone s3://git-remote-s3/test {}", repo3.display()),
+    )
+    .assert()
+    .success();
+    // The remote is listed once, rather than again for each ref fetched
+    assert_eq!(server().listings("git-remote-s3"), listings + 1);
+    git(&repo3, "rev-parse origin/feature")
+        .assert()
+        .stdout(format!("{}\n", feature));
+
+    println!("test: fetch errors");
+    git(&repo1, "commit --allow-empty -am r1_c7")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    server().fail("GET", "refs/heads/master/", 403, "AccessDenied", 1);
+    let out = git(&repo3, "fetch origin").output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("couldn't fetch refs/heads/master"));
+    assert!(stderr.contains("access denied to s3://git-remote-s3/test/bundles/refs/heads/master/"));
+    git(&repo3, "fetch origin").assert().success();
+
+    println!("test: large pushes are uploaded in parts");
+    // Random, so that it doesn't compress to less than a part
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let data = (0..9 * 1024 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect::<Vec<_>>();
+    fs::write(repo1.join("large.bin"), data).unwrap();
+    git(&repo1, "add large.bin").assert().success();
+    git(&repo1, "commit -m large").assert().success();
+    // Starting the multipart upload fails, so the push does
+    server().fail("POST", "refs/heads/master/", 500, "InternalError", 1);
+    git(&repo1, "push origin master").assert().failure();
+    git(&repo1, "push origin master").assert().success();
+    git(&repo3, "pull origin master").assert().success();
+    assert_eq!(
+        fs::read(repo1.join("large.bin")).unwrap(),
+        fs::read(repo3.join("large.bin")).unwrap()
+    );
+}
+
+// The same workflow on a directory, which needs no s3 server
+#[test]
+fn file_storage() {
+    let test_dir = Builder::new()
+        .prefix("git_s3_file_test")
+        .tempdir()
+        .expect("mktemp dir failed");
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo2).unwrap();
+    let url = format!("file://{}/store/test", test_dir.path().display());
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "commit --allow-empty -am r1_c1")
+        .assert()
+        .success();
+    git(&repo1, &format!("remote add origin s3::{}", url))
+        .assert()
+        .success();
+    git(&repo1, "push --set-upstream origin master")
+        .assert()
+        .success();
+
+    git(&repo2, &format!("clone s3::{} .", url))
+        .assert()
+        .success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo2));
+    git(&repo2, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo2, "config user.name Test").assert().success();
+    git(&repo2, "commit --allow-empty -am r2_c1")
+        .assert()
+        .success();
+    git(&repo2, "push origin").assert().success();
+
+    git(&repo1, "pull").assert().success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo2));
+
+    // A force push keeps the previous head as a stale one
+    git(&repo1, "commit --allow-empty --amend -m r1_c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin").assert().failure();
+    git(&repo1, "push -f origin").assert().success();
+    let sha1 = git_rev_long(&repo1);
+    let sha2 = git_rev_long(&repo2);
+    git(&repo2, "ls-remote origin").assert().stdout(format!(
+        "{}\trefs/heads/master\n{}\trefs/heads/master__{}\n{}\tHEAD\n",
+        sha1,
+        sha2,
+        &sha2[0..7],
+        sha1
+    ));
+
+    // The same heads through the library
+    let remote = Remote::open(
+        &url,
+        Config {
+            alias: "library".to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let refs = remote.list_refs().unwrap();
+    let heads = refs["refs/heads/master"]
+        .latest_first
+        .iter()
+        .map(|r| r.reference.sha.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(heads, vec![sha1.as_str(), sha2.as_str()]);
+
+    // Heads pushed without a push time keep their order when copied
+    let store = test_dir.path().join("store");
+    for entry in fs::read_dir(store.join("test/refs/heads/master")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.to_string_lossy().ends_with(".metadata") {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    let listed = format!(
+        "{}\trefs/heads/master\n{}\trefs/heads/master__{}\n{}\tHEAD\n",
+        sha1,
+        sha2,
+        &sha2[0..7],
+        sha1
+    );
+    let mirror = format!("file://{}/mirror", store.display());
+    admin(&repo1, &format!("mirror origin {}", mirror))
+        .assert()
+        .success();
+    admin(&repo1, "migrate origin --yes").assert().success();
+    git(&repo1, &format!("remote add mirror s3::{}", mirror))
+        .assert()
+        .success();
+    for remote in ["mirror", "origin"].iter() {
+        git(&repo1, &format!("ls-remote {}", remote))
+            .assert()
+            .stdout(listed.to_owned());
+        let out = admin(&repo1, &format!("heads {} master", remote))
+            .output()
+            .unwrap();
+        let heads = String::from_utf8(out.stdout).unwrap();
+        assert_eq!(heads.matches("    pushed-at: ").count(), 2, "{}", heads);
+    }
+
+    // Imported stale heads stay older than the latest one
+    let export = test_dir.path().join("export.git");
+    admin(&repo1, &format!("export origin {}", export.display()))
+        .assert()
+        .success();
+    let imported = format!("file://{}/imported", store.display());
+    admin(
+        &repo1,
+        &format!("import {} {}", export.display(), imported),
+    )
+    .assert()
+    .success();
+    git(&repo1, &format!("ls-remote s3::{}", imported))
+        .assert()
+        .stdout(listed.to_owned());
+
+    admin(&repo1, "verify origin").assert().success();
+    admin(&repo1, "prune origin --force --older-than 0s --yes")
+        .assert()
+        .success();
+    git(&repo2, "ls-remote origin")
+        .assert()
+        .stdout(format!("{}\trefs/heads/master\n{}\tHEAD\n", sha1, sha1));
+
+    // Bundles compressed with zstd record it, so clones decompress them
+    // whatever their own setting
+    git(&repo1, "config remote.origin.compression zstd")
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am r1_c3")
+        .assert()
+        .success();
+    git(&repo1, "push origin").assert().success();
+    git(&repo2, "fetch origin").assert().success();
+    git(&repo2, "reset -q --hard origin/master")
+        .assert()
+        .success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo2));
+    let out = admin(&repo1, "heads origin master").output().unwrap();
+    let heads = String::from_utf8(out.stdout).unwrap();
+    assert!(heads.contains("    compression: zstd\n"), "{}", heads);
+    admin(&repo1, "verify origin").assert().success();
+
+    git(&repo1, "config remote.origin.compression lz4")
+        .assert()
+        .success();
+    git(&repo1, "commit --allow-empty -am r1_c4")
+        .assert()
+        .success();
+    git(&repo1, "push origin").assert().failure();
+}
+
+// Compares the codecs on this repository's own history, run with
+// `cargo test -- --ignored --nocapture compression_benchmark`
+#[test]
+#[ignore]
+fn compression_benchmark() {
+    let test_dir = Builder::new()
+        .prefix("git_s3_compression")
+        .tempdir()
+        .expect("mktemp dir failed");
+    let sample = test_dir.path().join("sample");
+    let sample_arg = sample.to_str().unwrap();
+    git(
+        Path::new(env!("CARGO_MANIFEST_DIR")),
+        &format!("clone -q . {}", sample_arg),
+    )
+    .assert()
+    .success();
+    git(&sample, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&sample, "config user.name Test").assert().success();
+    let branch = git(&sample, "symbolic-ref --short HEAD").output().unwrap();
+    let branch = String::from_utf8(branch.stdout).unwrap().trim().to_string();
+
+    println!("{:<12} {:>12} {:>10} {:>10}", "codec", "bytes", "push", "fetch");
+    for codec in ["none", "zstd", "gpg-default"] {
+        let store = test_dir.path().join(codec);
+        let url = format!("s3::file://{}/repo", store.display());
+        git(&sample, &format!("remote add {} {}", codec, url))
+            .assert()
+            .success();
+        git(&sample, &format!("config remote.{}.compression {}", codec, codec))
+            .assert()
+            .success();
+
+        let start = Instant::now();
+        git(&sample, &format!("push -q {} {}", codec, branch))
+            .assert()
+            .success();
+        let push = start.elapsed();
+
+        let bundle = fs::read_dir(store.join("repo").join("refs/heads").join(&branch))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "bundle"))
+            .unwrap();
+        let size = fs::metadata(bundle).unwrap().len();
+
+        let clone = test_dir.path().join(format!("{}-clone", codec));
+        let start = Instant::now();
+        git(
+            test_dir.path(),
+            &format!("clone -q -b {} {} {}", branch, url, clone.display()),
+        )
+        .assert()
+        .success();
+        let fetch = start.elapsed();
+        assert_eq!(git_rev(&sample), git_rev(&clone));
+
+        println!("{:<12} {:>12} {:>10.2?} {:>10.2?}", codec, size, push, fetch);
+    }
+}
+
+#[test]
+fn packs() {
+    let test_dir = Builder::new()
+        .prefix("git_s3_packs_test")
+        .tempdir()
+        .expect("mktemp dir failed");
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    fs::create_dir(&repo1).unwrap();
+    let store = test_dir.path().join("store").join("test");
+    let url = format!("file://{}", store.display());
+    let packs_in_store = || {
+        let mut packs = fs::read_dir(store.join("packs"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| n.ends_with(".bundle"))
+            .collect::<Vec<_>>();
+        packs.sort();
+        packs
+    };
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    git(&repo1, "commit --allow-empty -am r1_c1")
+        .assert()
+        .success();
+    let c1 = git_rev_long(&repo1);
+    git(&repo1, &format!("remote add origin s3::{}", url))
+        .assert()
+        .success();
+    admin(&repo1, "init origin --packs").assert().success();
+    git(&repo1, "push origin master").assert().success();
+    assert_eq!(packs_in_store(), vec![format!("{}.bundle", c1)]);
+
+    // A branch only adds a pack for the commits master doesn't have, and a
+    // branch of a commit that was already pushed adds none
+    git(&repo1, "checkout -q -b feature").assert().success();
+    git(&repo1, "commit --allow-empty -am feature_c1")
+        .assert()
+        .success();
+    let f1 = git_rev_long(&repo1);
+    git(&repo1, "push origin feature").assert().success();
+    git(&repo1, "branch copy master").assert().success();
+    git(&repo1, "push origin copy").assert().success();
+    let mut expected = vec![format!("{}.bundle", c1), format!("{}.bundle", f1)];
+    expected.sort();
+    assert_eq!(packs_in_store(), expected);
+    let manifest = fs::read_to_string(store.join(format!(
+        "heads/refs/heads/feature/{}.json",
+        f1
+    )))
+    .unwrap();
+    assert!(
+        manifest.find(&c1).unwrap() < manifest.find(&f1).unwrap(),
+        "{}",
+        manifest
+    );
+
+    git(
+        test_dir.path(),
+        &format!("clone -q s3::{} {}", url, repo2.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev(&repo2), &c1[0..7]);
+    git(&repo2, "checkout -q feature").assert().success();
+    assert_eq!(git_rev_long(&repo2), f1);
+
+    // Fetching only downloads the packs of commits the clone doesn't have,
+    // so one it already has can be missing
+    git(&repo1, "checkout -q master").assert().success();
+    git(&repo1, "merge -q --no-edit feature").assert().success();
+    git(&repo1, "commit --allow-empty -am r1_c2")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    let c1_pack = store.join("packs").join(format!("{}.bundle", c1));
+    let c1_pack_data = fs::read(&c1_pack).unwrap();
+    fs::remove_file(&c1_pack).unwrap();
+    git(&repo2, "checkout -q master").assert().success();
+    git(&repo2, "pull -q").assert().success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo2));
+    fs::write(&c1_pack, c1_pack_data).unwrap();
+
+    let export = test_dir.path().join("export");
+    admin(&repo1, &format!("export origin {}", export.display()))
+        .assert()
+        .success();
+    assert_eq!(
+        git(&export, "rev-parse refs/heads/feature")
+            .output()
+            .unwrap()
+            .stdout,
+        format!("{}\n", f1).into_bytes()
+    );
+
+    // gc repacks every head into one pack, keeping the packs it replaced
+    // until they have been unused for the grace period
+    let before = packs_in_store();
+    admin(&repo1, "gc origin").assert().success();
+    let after = packs_in_store();
+    assert_eq!(after.len(), before.len() + 1);
+    let gc_pack = after.iter().find(|p| p.starts_with("gc-")).unwrap().clone();
+    admin(&repo1, "gc origin --grace=0s").assert().success();
+    assert_eq!(packs_in_store(), vec![gc_pack.to_owned()]);
+
+    git(&repo1, "commit --allow-empty -am r1_c3")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    assert_eq!(packs_in_store().len(), 2);
+    let repo3 = test_dir.path().join("repo3");
+    git(
+        test_dir.path(),
+        &format!("clone -q s3::{} {}", url, repo3.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo3));
+    git(&repo3, "checkout -q feature").assert().success();
+    assert_eq!(git_rev_long(&repo3), f1);
+    git(&repo2, "pull -q").assert().success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo2));
+
+    // The packs are what rekey encrypts, as the heads are only manifests
+    admin(
+        &repo1,
+        "init origin --force --packs --recipients=test@example.com,second@example.com",
+    )
+    .assert()
+    .success();
+    let out = admin(&repo1, "rekey origin --yes").output().unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout.matches(".bundle: re-encrypted\n").count(), 2, "{}", stdout);
+    let out = admin(&repo1, "rekey origin --yes").output().unwrap();
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .ends_with("nothing to rekey\n"));
+    let repo4 = test_dir.path().join("repo4");
+    git(
+        test_dir.path(),
+        &format!("clone -q s3::{} {}", url, repo4.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev(&repo1), git_rev(&repo4));
+
+    let out = admin(&repo1, "get origin master bundle").output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("isn't supported on remotes using packs"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn shallow() {
+    let test_dir = Builder::new()
+        .prefix("git_s3_shallow_test")
+        .tempdir()
+        .expect("mktemp dir failed");
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    let repo3 = test_dir.path().join("repo3");
+    fs::create_dir(&repo1).unwrap();
+    let store = test_dir.path().join("store").join("test");
+    let url = format!("file://{}", store.display());
+    let commit_count = |repo: &Path| {
+        let out = git(repo, "rev-list --count HEAD").output().unwrap();
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    };
+    let is_shallow = |repo: &Path| {
+        let out = git(repo, "rev-parse --is-shallow-repository")
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap().trim() == "true"
+    };
+
+    git(&repo1, "init").assert().success();
+    git(&repo1, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo1, "config user.name Test").assert().success();
+    for message in &["r1_c1", "r1_c2", "r1_c3"] {
+        git(&repo1, &format!("commit --allow-empty -am {}", message))
+            .assert()
+            .success();
+    }
+    git(&repo1, &format!("remote add origin s3::{}", url))
+        .assert()
+        .success();
+    admin(&repo1, "init origin --snapshots").assert().success();
+    git(&repo1, "push origin master").assert().success();
+    let c3 = git_rev_long(&repo1);
+    assert!(store
+        .join(format!("snapshots/refs/heads/master/{}.pack", c3))
+        .exists());
+
+    // A clone with --depth=1 only downloads the snapshot
+    git(
+        test_dir.path(),
+        &format!("clone -q --depth 1 s3::{} {}", url, repo2.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev_long(&repo2), c3);
+    assert_eq!(commit_count(&repo2), "1");
+    assert!(is_shallow(&repo2));
+
+    // Unshallowing fetches the whole head
+    git(&repo2, "fetch -q --unshallow").assert().success();
+    assert_eq!(commit_count(&repo2), "3");
+    assert!(!is_shallow(&repo2));
+    git(&repo2, "fsck").assert().success();
+
+    // Mirrors keep the snapshots, re-encrypted along with the heads
+    for (name, args) in [
+        ("copy", ""),
+        ("reencrypted", " --recipients=second@example.com"),
+    ]
+    .iter()
+    {
+        let mirror = test_dir.path().join("store").join(name);
+        admin(
+            &repo1,
+            &format!("mirror origin file://{}{}", mirror.display(), args),
+        )
+        .assert()
+        .success();
+        assert!(mirror
+            .join(format!("snapshots/refs/heads/master/{}.pack", c3))
+            .exists());
+        let clone = test_dir.path().join(format!("clone_{}", name));
+        git(
+            test_dir.path(),
+            &format!(
+                "clone -q --depth 1 s3::file://{} {}",
+                mirror.display(),
+                clone.display()
+            ),
+        )
+        .assert()
+        .success();
+        assert_eq!(commit_count(&clone), "1");
+    }
+
+    // Snapshots are deleted along with their heads
+    git(&repo1, "commit --allow-empty -am r1_c4")
+        .assert()
+        .success();
+    git(&repo1, "push origin master").assert().success();
+    assert!(!store
+        .join(format!("snapshots/refs/heads/master/{}.pack", c3))
+        .exists());
+
+    // Remotes without snapshots clone the whole head
+    let store2 = test_dir.path().join("store").join("full");
+    let url2 = format!("file://{}", store2.display());
+    git(&repo1, &format!("remote add full s3::{}", url2))
+        .assert()
+        .success();
+    git(&repo1, "push full master").assert().success();
+    assert!(!store2.join("snapshots").exists());
+    git(
+        test_dir.path(),
+        &format!("clone -q --depth 1 s3::{} {}", url2, repo3.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(commit_count(&repo3), "4");
+}
+
+#[test]
+fn sha256() {
+    let test_dir = Builder::new()
+        .prefix("git_s3_sha256_test")
+        .tempdir()
+        .expect("mktemp dir failed");
+    let repo1 = test_dir.path().join("repo1");
+    let repo2 = test_dir.path().join("repo2");
+    let repo3 = test_dir.path().join("repo3");
+    fs::create_dir(&repo1).unwrap();
+    fs::create_dir(&repo3).unwrap();
+    let store = test_dir.path().join("store").join("test");
+    let url = format!("file://{}", store.display());
+    let setup = |repo: &Path, args: &str| {
+        git(repo, args).assert().success();
+        git(repo, "config user.email test@example.com")
+            .assert()
+            .success();
+        git(repo, "config user.name Test").assert().success();
+        git(repo, &format!("remote add origin s3::{}", url))
+            .assert()
+            .success();
+    };
+
+    setup(&repo1, "init --object-format=sha256");
+    git(&repo1, "commit --allow-empty -am r1_c1")
+        .assert()
+        .success();
+    admin(&repo1, "init origin").assert().success();
+    let config = fs::read_to_string(store.join(".git-remote-s3.json")).unwrap();
+    assert!(config.contains("\"object_format\": \"sha256\""), "{}", config);
+    git(&repo1, "push origin master").assert().success();
+    let c1 = git_rev_long(&repo1);
+    assert_eq!(c1.len(), 64);
+
+    // A remote without a config keeps its heads' hash algorithm when migrated
+    let legacy = test_dir.path().join("store").join("legacy");
+    git(
+        &repo1,
+        &format!("remote add legacy s3::file://{}", legacy.display()),
+    )
+    .assert()
+    .success();
+    git(&repo1, "push legacy master").assert().success();
+    admin(&repo1, "migrate legacy --yes").assert().success();
+    let config = fs::read_to_string(legacy.join(".git-remote-s3.json")).unwrap();
+    assert!(config.contains("\"object_format\": \"sha256\""), "{}", config);
+    git(&repo1, "branch other").assert().success();
+    git(&repo1, "push legacy other").assert().success();
+
+    // A clone is created with the remote's hash algorithm
+    git(
+        test_dir.path(),
+        &format!("clone -q s3::{} {}", url, repo2.display()),
+    )
+    .assert()
+    .success();
+    assert_eq!(git_rev_long(&repo2), c1);
+    let out = git(&repo2, "rev-parse --show-object-format")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(out.stdout).unwrap().trim(), "sha256");
+
+    // Old heads are named after the start of their sha, as with sha1
+    git(&repo2, "config user.email test@example.com")
+        .assert()
+        .success();
+    git(&repo2, "config user.name Test").assert().success();
+    git(&repo2, "commit --allow-empty -am r2_c2")
+        .assert()
+        .success();
+    git(&repo2, "push origin master").assert().success();
+    let c2 = git_rev_long(&repo2);
+    git(&repo1, "commit --allow-empty -am r1_c2")
+        .assert()
+        .success();
+    git(&repo1, "push -f origin master").assert().success();
+    let out = git(&repo1, "ls-remote origin").output().unwrap();
+    let ls = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        ls.contains(&format!("{}\trefs/heads/master__{}", c2, &c2[..7])),
+        "{}",
+        ls
+    );
+
+    // sha1 repositories can't push to or fetch from it
+    setup(&repo3, "init --object-format=sha1");
+    git(&repo3, "commit --allow-empty -am r3_c1")
+        .assert()
+        .success();
+    let out = git(&repo3, "push origin master").output().unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("holds sha256 repositories, and this one is sha1"),
+        "{}",
+        stderr
+    );
+    git(&repo3, "fetch origin").assert().failure();
+    let heads = fs::read_dir(store.join("bundles/refs/heads/master"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n.ends_with(".bundle"))
+        .collect::<Vec<_>>();
+    assert_eq!(heads.len(), 2, "{:?}", heads);
+    assert!(heads.iter().all(|n| n.len() == 64 + ".bundle".len()));
 }
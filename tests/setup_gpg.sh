@@ -2,11 +2,6 @@
 
 set -euo pipefail
 
-if gpg --fingerprint --with-colons 'test@example.com' | grep "example.com" ; then 
-  echo Key for test@example.com exists
-  exit
-fi
-
 todelete() {
   gpg --fingerprint --with-colons 'test@example.com' |\
     grep "^fpr" |\
@@ -25,9 +20,9 @@ keydetails() {
     Key-Length: 2048
     Subkey-Type: RSA
     Subkey-Length: 2048
-    Name-Real: Test User
-    Name-Comment: Test User
-    Name-Email: test@example.com
+    Name-Real: $1
+    Name-Comment: $1
+    Name-Email: $2
     Expire-Date: 0
     %no-ask-passphrase
     %no-protection
@@ -39,7 +34,15 @@ keydetails() {
 EOF
 }
 
-gpg --verbose --batch --gen-key <(keydetails)
+# The tests encrypt for test@example.com, and rekey for a second recipient
+for key in "Test User:test@example.com" "Second User:second@example.com"; do
+  email=${key#*:}
+  if gpg --fingerprint --with-colons "$email" | grep "example.com" ; then
+    echo Key for $email exists
+    continue
+  fi
+  gpg --verbose --batch --gen-key <(keydetails "${key%%:*}" "$email")
+done
 
 # Set trust to 5 for the key so we can encrypt without prompt.
 #echo -e "5\ny\n" |  gpg --command-fd 0 --expert --edit-key 'test@example.com' trust;
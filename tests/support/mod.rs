@@ -0,0 +1,562 @@
+// A small in-process s3 server, implementing the subset of the api the helper
+// uses (path style requests, no authentication), so the tests don't need one
+// running separately.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+struct Object {
+    data: Vec<u8>,
+    metadata: BTreeMap<String, String>,
+    storage_class: Option<String>,
+    last_modified: DateTime<Utc>,
+}
+
+struct Upload {
+    bucket: String,
+    key: String,
+    metadata: BTreeMap<String, String>,
+    storage_class: Option<String>,
+    parts: BTreeMap<u32, Vec<u8>>,
+}
+
+// A response to give instead of handling matching requests
+struct Fault {
+    method: String,
+    key_contains: String,
+    status: u16,
+    code: String,
+    times: usize,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: BTreeMap<String, BTreeMap<String, Object>>,
+    uploads: HashMap<String, Upload>,
+    next_upload: u64,
+    faults: Vec<Fault>,
+    page_size: Option<usize>,
+    // Listings started per bucket, however many pages they took
+    listings: HashMap<String, usize>,
+}
+
+pub struct FakeS3 {
+    endpoint: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeS3 {
+    pub fn start() -> FakeS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind fake s3");
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+        FakeS3 { endpoint, state }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    // Fail the next `times` requests with the method whose key contains
+    // `key_contains`, with an s3 error
+    pub fn fail(&self, method: &str, key_contains: &str, status: u16, code: &str, times: usize) {
+        self.state.lock().unwrap().faults.push(Fault {
+            method: method.to_string(),
+            key_contains: key_contains.to_string(),
+            status,
+            code: code.to_string(),
+            times,
+        });
+    }
+
+    // Change the metadata of objects directly, e.g. as an interrupted
+    // overwrite that isn't atomic would leave it
+    pub fn set_metadata(&self, bucket: &str, key_contains: &str, name: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        for (key, object) in state.buckets.get_mut(bucket).unwrap().iter_mut() {
+            if key.contains(key_contains) {
+                object.metadata.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+
+    // How many times the bucket has been listed
+    pub fn listings(&self, bucket: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.listings.get(bucket).copied().unwrap_or(0)
+    }
+
+    // List at most this many objects per response, to exercise pagination
+    pub fn set_page_size(&self, page_size: usize) {
+        self.state.lock().unwrap().page_size = Some(page_size);
+    }
+}
+
+struct Request {
+    method: String,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn xml(status: u16, body: String) -> Response {
+        let mut response = Response::new(status);
+        response
+            .headers
+            .push(("Content-Type".to_string(), "application/xml".to_string()));
+        response.body = body.into_bytes();
+        response
+    }
+
+    fn error(status: u16, code: &str) -> Response {
+        Response::xml(
+            status,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message></Error>",
+                code, code
+            ),
+        )
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    // Connections are kept alive, as the client reuses them
+    while let Some(request) = read_request(&mut reader) {
+        let head = request.method == "HEAD";
+        let response = handle(state, request);
+        let reason = match response.status {
+            200 => "OK",
+            204 => "No Content",
+            206 => "Partial Content",
+            403 => "Forbidden",
+            404 => "Not Found",
+            _ => "Error",
+        };
+        let mut out = format!("HTTP/1.1 {} {}\r\n", response.status, reason);
+        for (name, value) in response.headers.iter() {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !response.headers.iter().any(|(n, _)| n == "Content-Length") {
+            out.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+        }
+        out.push_str("\r\n");
+        let mut out = out.into_bytes();
+        if !head {
+            out.extend_from_slice(&response.body);
+        }
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let idx = line.find(':')?;
+        headers.insert(
+            line[..idx].trim().to_ascii_lowercase(),
+            line[(idx + 1)..].trim().to_string(),
+        );
+    }
+
+    let mut body = vec![];
+    if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim().split(';').next()?, 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        body = vec![0; len.parse().ok()?];
+        reader.read_exact(&mut body).ok()?;
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(idx) => (&target[..idx], &target[(idx + 1)..]),
+        None => (target.as_str(), ""),
+    };
+    let path = decode(path.trim_start_matches('/'));
+    let (bucket, key) = match path.find('/') {
+        Some(idx) => (path[..idx].to_string(), path[(idx + 1)..].to_string()),
+        None => (path, String::new()),
+    };
+    let query = query
+        .split('&')
+        .filter(|q| !q.is_empty())
+        .map(|q| match q.find('=') {
+            Some(idx) => (decode(&q[..idx]), decode(&q[(idx + 1)..])),
+            None => (decode(q), String::new()),
+        })
+        .collect();
+    Some(Request {
+        method,
+        bucket,
+        key,
+        query,
+        headers,
+        body,
+    })
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match u8::from_str_radix(&s[(i + 1)..(i + 3)], 16) {
+                Ok(b) => {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                Err(_) => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn etag(data: &[u8]) -> String {
+    // Not an md5, but stable for the same contents
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x100_0000_01b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+fn object_headers(object: &Object) -> Vec<(String, String)> {
+    let mut headers = vec![
+        ("ETag".to_string(), etag(&object.data)),
+        (
+            "Last-Modified".to_string(),
+            object
+                .last_modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ),
+    ];
+    for (name, value) in object.metadata.iter() {
+        headers.push((format!("x-amz-meta-{}", name), value.to_owned()));
+    }
+    if let Some(storage_class) = &object.storage_class {
+        headers.push(("x-amz-storage-class".to_string(), storage_class.to_owned()));
+    }
+    headers
+}
+
+fn metadata(request: &Request) -> BTreeMap<String, String> {
+    request
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                name.strip_prefix("x-amz-meta-")?.to_string(),
+                value.to_owned(),
+            ))
+        })
+        .collect()
+}
+
+fn handle(state: &Mutex<State>, request: Request) -> Response {
+    let mut state = state.lock().unwrap();
+
+    if let Some(fault) = state.faults.iter_mut().find(|f| {
+        f.times > 0 && f.method == request.method && request.key.contains(&f.key_contains)
+    }) {
+        fault.times -= 1;
+        return Response::error(fault.status, &fault.code);
+    }
+
+    if request.key.is_empty() {
+        return handle_bucket(&mut state, request);
+    }
+    if !state.buckets.contains_key(&request.bucket) {
+        return Response::error(404, "NoSuchBucket");
+    }
+
+    match request.method.as_str() {
+        "GET" | "HEAD" => {
+            let object = match state.buckets[&request.bucket].get(&request.key) {
+                Some(object) => object,
+                None if request.method == "HEAD" => return Response::new(404),
+                None => return Response::error(404, "NoSuchKey"),
+            };
+            let mut response = Response::new(200);
+            response.headers = object_headers(object);
+            response.body = object.data.clone();
+            if let Some(range) = request
+                .headers
+                .get("range")
+                .and_then(|r| r.strip_prefix("bytes="))
+            {
+                let mut split = range.splitn(2, '-');
+                let start: usize = split.next().unwrap().parse().unwrap_or(0);
+                let end: usize = split
+                    .next()
+                    .and_then(|e| e.parse().ok())
+                    .unwrap_or(usize::MAX)
+                    .min(object.data.len().saturating_sub(1));
+                response.status = 206;
+                response.body = object.data[start.min(end + 1)..=end].to_vec();
+                response.headers.push((
+                    "Content-Range".to_string(),
+                    format!("bytes {}-{}/{}", start, end, object.data.len()),
+                ));
+            }
+            if request.method == "HEAD" {
+                response
+                    .headers
+                    .push(("Content-Length".to_string(), object.data.len().to_string()));
+            }
+            response
+        }
+        "PUT" if request.query.contains_key("uploadId") => {
+            let upload = match state.uploads.get_mut(&request.query["uploadId"]) {
+                Some(upload) => upload,
+                None => return Response::error(404, "NoSuchUpload"),
+            };
+            let part: u32 = request.query["partNumber"].parse().unwrap();
+            let mut response = Response::new(200);
+            response
+                .headers
+                .push(("ETag".to_string(), etag(&request.body)));
+            upload.parts.insert(part, request.body);
+            response
+        }
+        "PUT" => {
+            let (data, metadata) = match request.headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = decode(source.trim_start_matches('/'));
+                    let idx = source.find('/').unwrap();
+                    let (bucket, key) = (&source[..idx], &source[(idx + 1)..]);
+                    let replace = request
+                        .headers
+                        .get("x-amz-metadata-directive")
+                        .is_some_and(|d| d == "REPLACE");
+                    match state.buckets.get(bucket).and_then(|b| b.get(key)) {
+                        Some(object) if replace => (object.data.clone(), metadata(&request)),
+                        Some(object) => (object.data.clone(), object.metadata.clone()),
+                        None => return Response::error(404, "NoSuchKey"),
+                    }
+                }
+                None => (request.body.clone(), metadata(&request)),
+            };
+            let object = Object {
+                data,
+                metadata,
+                storage_class: request.headers.get("x-amz-storage-class").cloned(),
+                last_modified: Utc::now(),
+            };
+            let tag = etag(&object.data);
+            state
+                .buckets
+                .get_mut(&request.bucket)
+                .unwrap()
+                .insert(request.key.to_owned(), object);
+            if request.headers.contains_key("x-amz-copy-source") {
+                return Response::xml(
+                    200,
+                    format!(
+                        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<CopyObjectResult><ETag>{}</ETag><LastModified>{}</LastModified></CopyObjectResult>",
+                        escape(&tag),
+                        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+                    ),
+                );
+            }
+            let mut response = Response::new(200);
+            response.headers.push(("ETag".to_string(), tag));
+            response
+        }
+        "POST" if request.query.contains_key("uploads") => {
+            state.next_upload += 1;
+            let upload_id = format!("upload{}", state.next_upload);
+            let upload = Upload {
+                bucket: request.bucket.to_owned(),
+                key: request.key.to_owned(),
+                metadata: metadata(&request),
+                storage_class: request.headers.get("x-amz-storage-class").cloned(),
+                parts: BTreeMap::new(),
+            };
+            state.uploads.insert(upload_id.to_owned(), upload);
+            Response::xml(
+                200,
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    escape(&request.bucket),
+                    escape(&request.key),
+                    upload_id
+                ),
+            )
+        }
+        "POST" if request.query.contains_key("uploadId") => {
+            let upload = match state.uploads.remove(&request.query["uploadId"]) {
+                Some(upload) => upload,
+                None => return Response::error(404, "NoSuchUpload"),
+            };
+            let object = Object {
+                data: upload.parts.values().flatten().cloned().collect(),
+                metadata: upload.metadata,
+                storage_class: upload.storage_class,
+                last_modified: Utc::now(),
+            };
+            let tag = etag(&object.data);
+            state
+                .buckets
+                .get_mut(&upload.bucket)
+                .unwrap()
+                .insert(upload.key.to_owned(), object);
+            Response::xml(
+                200,
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                    escape(&upload.bucket),
+                    escape(&upload.key),
+                    escape(&tag)
+                ),
+            )
+        }
+        "DELETE" if request.query.contains_key("uploadId") => {
+            state.uploads.remove(&request.query["uploadId"]);
+            Response::new(204)
+        }
+        "DELETE" => {
+            state
+                .buckets
+                .get_mut(&request.bucket)
+                .unwrap()
+                .remove(&request.key);
+            Response::new(204)
+        }
+        _ => Response::error(405, "MethodNotAllowed"),
+    }
+}
+
+fn handle_bucket(state: &mut State, request: Request) -> Response {
+    match request.method.as_str() {
+        "PUT" => {
+            state.buckets.entry(request.bucket).or_default();
+            Response::new(200)
+        }
+        "DELETE" => {
+            state.buckets.remove(&request.bucket);
+            Response::new(204)
+        }
+        "GET" => {
+            let objects = match state.buckets.get(&request.bucket) {
+                Some(objects) => objects,
+                None => return Response::error(404, "NoSuchBucket"),
+            };
+            let prefix = request.query.get("prefix").cloned().unwrap_or_default();
+            let max_keys = request
+                .query
+                .get("max-keys")
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(1000)
+                .min(state.page_size.unwrap_or(1000));
+            // Continuation tokens are the last key of the previous page
+            let after = request
+                .query
+                .get("continuation-token")
+                .cloned()
+                .unwrap_or_default();
+            if after.is_empty() {
+                *state.listings.entry(request.bucket.to_owned()).or_default() += 1;
+            }
+            let matching = objects
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix) && (after.is_empty() || **k > after))
+                .collect::<Vec<_>>();
+            let page = &matching[..matching.len().min(max_keys)];
+            let truncated = matching.len() > page.len();
+            let mut xml = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+                escape(&request.bucket),
+                escape(&prefix),
+                page.len(),
+                max_keys,
+                truncated
+            );
+            for (key, object) in page.iter() {
+                xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>{}</StorageClass></Contents>",
+                    escape(key),
+                    object.last_modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    escape(&etag(&object.data)),
+                    object.data.len(),
+                    object.storage_class.as_deref().unwrap_or("STANDARD")
+                ));
+            }
+            if truncated {
+                xml.push_str(&format!(
+                    "<NextContinuationToken>{}</NextContinuationToken>",
+                    escape(page.last().unwrap().0)
+                ));
+            }
+            xml.push_str("</ListBucketResult>");
+            Response::xml(200, xml)
+        }
+        _ => Response::error(405, "MethodNotAllowed"),
+    }
+}